@@ -1,111 +1,327 @@
-use std::cell::Cell;
+use libc::{poll, pollfd, POLLIN};
+use std::fmt::Display;
 use std::ops::Deref;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
-use std::{array, thread};
+use std::os::fd::RawFd;
 
+#[cfg(loom)]
+use loom::{
+  sync::atomic::{AtomicUsize, Ordering},
+  sync::{Arc, Mutex, RwLock},
+  thread,
+};
+#[cfg(not(loom))]
+use std::{
+  sync::atomic::{AtomicUsize, Ordering},
+  sync::{Arc, Mutex, RwLock},
+  thread,
+};
+
+/// A slot holds the message with sequence number `seq`, both are only changed together while
+/// holding the write lock so readers always see a matching pair.
 #[derive(Debug)]
 struct Slot<T> {
-  version: AtomicUsize,
+  seq: AtomicUsize,
   message: RwLock<T>,
 }
 
+/// What `send` does when the slowest receiver is a full buffer behind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+  /// Overwrite the oldest message, slow receivers skip ahead.
+  Overwrite,
+  /// Wait until every receiver has room, nothing is ever dropped.
+  Block,
+}
+
+/// Returned by [`RingBuffer::try_send`] when the buffer is full in [`Overflow::Block`] mode.
+#[derive(Debug)]
+pub struct Full<T>(pub T);
+
+/// Position of a receiver in the buffer.
+#[derive(Debug)]
+struct Cursor {
+  last_seq: AtomicUsize,
+  /// Messages this receiver skipped because it was lapped.
+  dropped: AtomicUsize,
+  /// Whether it holds back senders in [`Overflow::Block`] mode.
+  blocks: bool,
+}
+
+#[derive(Debug)]
+struct Shared<T> {
+  slots: Box<[Slot<T>]>,
+  /// Sequence number the next sent message will get, sequence numbers start at 1.
+  head: AtomicUsize,
+  /// Number of messages fully published.
+  sent: AtomicUsize,
+  /// Messages skipped by receivers that are gone.
+  dropped: AtomicUsize,
+  receivers: Mutex<Vec<Arc<Cursor>>>,
+  overflow: Overflow,
+}
+
+/// Snapshot of a ring buffer's counters.
+#[derive(Debug, Clone)]
+pub struct Stats {
+  pub capacity: usize,
+  pub sent: usize,
+  /// Sequence number of the latest sent message.
+  pub version: usize,
+  /// Messages receivers skipped because they fell a full buffer behind, including receivers that
+  /// have been dropped since.
+  pub dropped: usize,
+  /// How many messages each live receiver has yet to read.
+  pub lags: Vec<usize>,
+}
+
+impl Display for Stats {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "sent {} | version {} | receivers {} | max lag {}/{} | dropped {}",
+      self.sent,
+      self.version,
+      self.lags.len(),
+      self.lags.iter().max().unwrap_or(&0),
+      self.capacity,
+      self.dropped,
+    )
+  }
+}
+
+/// Broadcast ring buffer, every receiver sees every message in send order unless it falls a full
+/// buffer behind in [`Overflow::Overwrite`] mode, then it skips to the oldest message still around.
+///
+/// Senders claim a sequence number from `head`, message `seq` lives in slot `seq % capacity` and a
+/// sender only writes it once the previous lap's message (`seq - capacity`) was published there.
+/// Receivers read sequence numbers in order and wait for the next one to be published, so messages
+/// are never seen out of order even with concurrent senders.
 #[derive(Debug)]
 pub struct RingBuffer<T> {
-  buffer: Arc<[Slot<T>]>,
-  write_index: Arc<AtomicUsize>,
-  version: Arc<AtomicUsize>,
+  shared: Arc<Shared<T>>,
 }
 
 impl<T> Clone for RingBuffer<T> {
   fn clone(&self) -> Self {
-    Self {
-      buffer: self.buffer.clone(),
-      write_index: self.write_index.clone(),
-      version: self.version.clone(),
+    Self { shared: self.shared.clone() }
+  }
+}
+
+impl<T> RingBuffer<T> {
+  /// Registers a receiver cursor starting at `last_seq`.
+  fn subscribe(&self, last_seq: usize, blocks: bool) -> Arc<Cursor> {
+    let cursor = Arc::new(Cursor {
+      last_seq: AtomicUsize::new(last_seq),
+      dropped: AtomicUsize::new(0),
+      blocks,
+    });
+    self.shared.receivers.lock().unwrap().push(cursor.clone());
+    cursor
+  }
+
+  fn unsubscribe(&self, cursor: &Arc<Cursor>) {
+    self.shared.receivers.lock().unwrap().retain(|c| !Arc::ptr_eq(c, cursor));
+    self.shared.dropped.fetch_add(cursor.dropped.load(Ordering::Acquire), Ordering::AcqRel);
+  }
+
+  pub fn stats(&self) -> Stats {
+    let version = self.latest_seq();
+    let receivers = self.shared.receivers.lock().unwrap();
+
+    Stats {
+      capacity: self.shared.slots.len(),
+      sent: self.shared.sent.load(Ordering::Acquire),
+      version,
+      dropped: self.shared.dropped.load(Ordering::Acquire) + receivers.iter().map(|c| c.dropped.load(Ordering::Acquire)).sum::<usize>(),
+      lags: receivers
+        .iter()
+        .map(|c| version.saturating_sub(c.last_seq.load(Ordering::Acquire)))
+        .collect(),
+    }
+  }
+
+  /// Sequence number of the most recently claimed message (0 if nothing was sent yet).
+  fn latest_seq(&self) -> usize {
+    self.shared.head.load(Ordering::Acquire) - 1
+  }
+
+  /// Whether writing `seq` would overwrite a message some receiver hasn't read yet.
+  fn is_full(&self, seq: usize) -> bool {
+    let capacity = self.shared.slots.len();
+    let receivers = self.shared.receivers.lock().unwrap();
+    receivers
+      .iter()
+      .any(|cursor| cursor.blocks && seq.saturating_sub(cursor.last_seq.load(Ordering::Acquire)) > capacity)
+  }
+
+  /// Claims the next sequence number, or `None` if that would overrun a receiver in
+  /// [`Overflow::Block`] mode.
+  fn claim(&self) -> Option<usize> {
+    if self.shared.overflow == Overflow::Overwrite {
+      return Some(self.shared.head.fetch_add(1, Ordering::AcqRel));
+    }
+
+    let mut seq = self.shared.head.load(Ordering::Acquire);
+    loop {
+      if self.is_full(seq) {
+        return None;
+      }
+
+      match self.shared.head.compare_exchange_weak(seq, seq + 1, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => return Some(seq),
+        Err(current) => seq = current,
+      }
     }
   }
 }
 
 impl<T: Copy> RingBuffer<T> {
-  pub fn new<const BUF_SIZE: usize>(value: T) -> Self {
+  pub fn new(capacity: usize, overflow: Overflow, value: T) -> Self {
+    assert!(capacity > 0, "RingBuffer capacity must be greater than 0");
+
     Self {
-      buffer: Arc::new(array::from_fn::<_, BUF_SIZE, _>(|_| Slot {
-        version: AtomicUsize::new(0),
-        message: RwLock::new(value),
-      })),
-      write_index: Arc::new(AtomicUsize::new(0)),
-      version: Arc::new(AtomicUsize::new(1)),
+      shared: Arc::new(Shared {
+        slots: (0..capacity)
+          .map(|_| Slot {
+            seq: AtomicUsize::new(0),
+            message: RwLock::new(value),
+          })
+          .collect(),
+        head: AtomicUsize::new(1),
+        sent: AtomicUsize::new(0),
+        dropped: AtomicUsize::new(0),
+        receivers: Mutex::new(Vec::new()),
+        overflow,
+      }),
     }
   }
 
-  pub fn channel<const BUF_SIZE: usize>(value: T) -> (Sender<T>, Receiver<T>) {
+  pub fn channel(capacity: usize, overflow: Overflow, value: T) -> (Sender<T>, Receiver<T>) {
+    let state = RingBuffer::new(capacity, overflow, value);
     let rx = Receiver {
-      state: RingBuffer::new::<BUF_SIZE>(value),
-      last_version: Cell::new(0),
+      cursor: state.subscribe(0, true),
+      state,
     };
 
     (Sender(rx.state.clone()), rx)
   }
 
+  /// Sends a message, in [`Overflow::Block`] mode this waits until the slowest receiver has room.
   pub fn send(&self, new_message: T) {
-    let index = self.write_index.fetch_add(1, Ordering::AcqRel) % self.buffer.len();
-    let version = self.version.fetch_add(1, Ordering::AcqRel);
+    loop {
+      if let Some(seq) = self.claim() {
+        return self.publish(seq, new_message);
+      }
+      backoff();
+    }
+  }
 
-    let slot = &self.buffer[index];
-    *slot.message.write().unwrap() = new_message;
-    slot.version.store(version, Ordering::Release);
+  /// Like `send` but returns the message back instead of waiting when the buffer is full.
+  pub fn try_send(&self, new_message: T) -> Result<(), Full<T>> {
+    match self.claim() {
+      Some(seq) => {
+        self.publish(seq, new_message);
+        Ok(())
+      }
+      None => Err(Full(new_message)),
+    }
+  }
+
+  fn publish(&self, seq: usize, new_message: T) {
+    let slots = &self.shared.slots;
+    let slot = &slots[seq % slots.len()];
+
+    // Senders a lap ahead must not overwrite a message that hasn't been published yet.
+    let prev_seq = seq.saturating_sub(slots.len());
+    while slot.seq.load(Ordering::Acquire) != prev_seq {
+      thread::yield_now();
+    }
+
+    let mut message = slot.message.write().unwrap();
+    *message = new_message;
+    slot.seq.store(seq, Ordering::Release);
+    self.shared.sent.fetch_add(1, Ordering::AcqRel);
   }
 }
 
+/// Receivers created from a [`Sender`] only see messages sent after they were created, clones
+/// continue from the same position as the original.
+///
+/// In [`Overflow::Block`] mode every live receiver holds back senders, so receivers that are no
+/// longer read from must be dropped, or be [`Receiver::lossy`] ones.
 #[derive(Debug)]
 pub struct Receiver<T> {
   state: RingBuffer<T>,
-  last_version: Cell<usize>,
+  cursor: Arc<Cursor>,
 }
 
 impl<T> Clone for Receiver<T> {
   fn clone(&self) -> Self {
     Self {
+      cursor: self.state.subscribe(self.cursor.last_seq.load(Ordering::Acquire), self.cursor.blocks),
       state: self.state.clone(),
-      last_version: Cell::new(0),
     }
   }
 }
 
+impl<T> Drop for Receiver<T> {
+  fn drop(&mut self) {
+    self.state.unsubscribe(&self.cursor);
+  }
+}
+
 impl<T> From<&Sender<T>> for Receiver<T> {
   fn from(value: &Sender<T>) -> Self {
     Self {
+      cursor: value.0.subscribe(value.0.latest_seq(), true),
       state: value.0.clone(),
-      last_version: Cell::new(0),
+    }
+  }
+}
+
+impl<T> Receiver<T> {
+  /// Like `Receiver::from(tx)` but never holds back senders, it skips ahead when lapped as in
+  /// [`Overflow::Overwrite`] mode.
+  pub fn lossy(tx: &Sender<T>) -> Self {
+    Self {
+      cursor: tx.0.subscribe(tx.0.latest_seq(), false),
+      state: tx.0.clone(),
     }
   }
 }
 
 impl<T: Copy> Receiver<T> {
   pub fn recv_some(&self) -> Option<T> {
-    let current_version = self.last_version.get() + 1;
-    for slot in self.state.buffer.iter() {
-      if slot.version.load(Ordering::Acquire) == current_version {
-        self.last_version.replace(current_version);
-        return Some(*slot.message.read().unwrap());
+    let slots = &self.state.shared.slots;
+
+    loop {
+      let next_seq = self.cursor.last_seq.load(Ordering::Acquire) + 1;
+      let slot = &slots[next_seq % slots.len()];
+
+      let (seq, message) = {
+        let message = slot.message.read().unwrap();
+        (slot.seq.load(Ordering::Acquire), *message)
+      };
+
+      if seq == next_seq {
+        self.cursor.last_seq.store(next_seq, Ordering::Release);
+        return Some(message);
       }
-    }
 
-    let next_closest = self
-      .state
-      .buffer
-      .iter()
-      .filter(|slot| slot.version.load(Ordering::Acquire) > current_version)
-      .min_by_key(|slot| slot.version.load(Ordering::Acquire));
+      if seq < next_seq {
+        return None;
+      }
 
-    if let Some(slot) = next_closest {
-      self.last_version.replace(slot.version.load(Ordering::Acquire));
-      return Some(*slot.message.read().unwrap());
+      // We were lapped, skip to the oldest message that can still be in the buffer.
+      let oldest_seq = seq - slots.len() + 1;
+      self.cursor.dropped.fetch_add(oldest_seq - next_seq, Ordering::AcqRel);
+      self.cursor.last_seq.store(oldest_seq - 1, Ordering::Release);
     }
+  }
 
-    None
+  /// Number of messages this receiver skipped because it fell a full buffer behind.
+  pub fn dropped(&self) -> usize {
+    self.cursor.dropped.load(Ordering::Acquire)
   }
 
   pub fn recv(&self) -> T {
@@ -113,9 +329,30 @@ impl<T: Copy> Receiver<T> {
       if let Some(message) = self.recv_some() {
         return message;
       }
-      thread::sleep(Duration::from_millis(1));
+      backoff();
+    }
+  }
+
+  /// Like `recv` but gives up after `timeout`.
+  #[cfg(not(loom))]
+  pub fn recv_timeout(&self, timeout: std::time::Duration) -> Option<T> {
+    let deadline = std::time::Instant::now() + timeout;
+    loop {
+      if let Some(message) = self.recv_some() {
+        return Some(message);
+      }
+      if std::time::Instant::now() >= deadline {
+        return None;
+      }
+      backoff();
     }
   }
+
+  /// Loom can't model time passing, so the timeout is already over after the first try.
+  #[cfg(loom)]
+  pub fn recv_timeout(&self, _timeout: std::time::Duration) -> Option<T> {
+    self.recv_some()
+  }
 }
 
 impl<T> Deref for Receiver<T> {
@@ -148,3 +385,335 @@ impl<T> From<&Receiver<T>> for Sender<T> {
     Self(value.state.clone())
   }
 }
+
+/// Result of a [`Select::select`] call.
+#[derive(Debug)]
+pub enum Selected<T> {
+  /// A message from one of the receivers.
+  Message(T),
+  /// The registered file descriptor is ready to be read.
+  Fd,
+}
+
+/// Waits on several receivers at once, optionally plus a raw file descriptor.
+///
+/// Receivers are checked in the order they were added, so earlier receivers take priority.
+pub struct Select<'a, T> {
+  receivers: Vec<&'a Receiver<T>>,
+  fd: Option<RawFd>,
+}
+
+impl<'a, T: Copy> Select<'a, T> {
+  pub fn new() -> Self {
+    Self {
+      receivers: Vec::new(),
+      fd: None,
+    }
+  }
+
+  pub fn recv(&mut self, rx: &'a Receiver<T>) -> &mut Self {
+    self.receivers.push(rx);
+    self
+  }
+
+  pub fn fd(&mut self, fd: RawFd) -> &mut Self {
+    self.fd = Some(fd);
+    self
+  }
+
+  pub fn select_some(&self) -> Option<Selected<T>> {
+    self.receivers.iter().find_map(|rx| rx.recv_some().map(Selected::Message))
+  }
+
+  pub fn select(&self) -> Selected<T> {
+    loop {
+      if let Some(selected) = self.select_some() {
+        return selected;
+      }
+
+      match self.fd {
+        Some(fd) if poll_fd(fd, 1) => return Selected::Fd,
+        Some(_) => (),
+        None => backoff(),
+      }
+    }
+  }
+}
+
+impl<T: Copy> Default for Select<'_, T> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Waits a bit before polling again, loom can't model sleeping so it yields instead.
+fn backoff() {
+  #[cfg(loom)]
+  thread::yield_now();
+  #[cfg(not(loom))]
+  thread::sleep(std::time::Duration::from_millis(1));
+}
+
+/// Returns true if `fd` is readable (or errored/hung up) within `timeout_ms`.
+fn poll_fd(fd: RawFd, timeout_ms: i32) -> bool {
+  let mut pfd = pollfd { fd, events: POLLIN, revents: 0 };
+
+  unsafe { poll(&mut pfd, 1, timeout_ms) != 0 }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+  use super::*;
+
+  const SENDERS: usize = 4;
+  const MESSAGES: usize = 2_000;
+
+  /// Spawns `SENDERS` threads, each sending `(sender, n)` for every `n` in `0..MESSAGES`.
+  fn spawn_senders(tx: &Sender<(usize, usize)>) -> Vec<thread::JoinHandle<()>> {
+    (0..SENDERS)
+      .map(|sender| {
+        let tx = tx.clone();
+        thread::spawn(move || (0..MESSAGES).for_each(|n| tx.send((sender, n))))
+      })
+      .collect()
+  }
+
+  #[test]
+  fn lapped_receiver_skips_to_oldest() {
+    let (tx, rx) = RingBuffer::channel(4, Overflow::Overwrite, 0);
+    (1..=10).for_each(|n| tx.send(n));
+
+    let received: Vec<_> = std::iter::from_fn(|| rx.recv_some()).collect();
+    assert_eq!(received, [7, 8, 9, 10]);
+    assert_eq!(rx.dropped(), 6);
+  }
+
+  #[test]
+  fn stats_track_lag_and_drops() {
+    let (tx, rx) = RingBuffer::channel(4, Overflow::Overwrite, 0);
+    let slow = rx.clone();
+    (1..=6).for_each(|n| tx.send(n));
+
+    assert_eq!(rx.recv_some(), Some(3));
+    let stats = tx.stats();
+    assert_eq!((stats.sent, stats.version, stats.dropped), (6, 6, 2));
+    assert_eq!(stats.lags, [3, 6]);
+
+    drop(rx);
+    assert_eq!(slow.recv_some(), Some(3));
+    let stats = tx.stats();
+    assert_eq!((stats.dropped, stats.lags.len()), (4, 1));
+  }
+
+  #[test]
+  fn receiver_from_sender_only_sees_new_messages() {
+    let (tx, _rx) = RingBuffer::channel(4, Overflow::Overwrite, 0);
+    tx.send(1);
+
+    let rx = Receiver::from(&tx);
+    assert_eq!(rx.recv_some(), None);
+    tx.send(2);
+    assert_eq!(rx.recv_some(), Some(2));
+  }
+
+  #[test]
+  fn try_send_returns_full_in_block_mode() {
+    let (tx, rx) = RingBuffer::channel(2, Overflow::Block, 0);
+    assert!(tx.try_send(1).is_ok());
+    assert!(tx.try_send(2).is_ok());
+    assert!(matches!(tx.try_send(3), Err(Full(3))));
+
+    assert_eq!(rx.recv_some(), Some(1));
+    assert!(tx.try_send(3).is_ok());
+    assert_eq!(std::iter::from_fn(|| rx.recv_some()).collect::<Vec<_>>(), [2, 3]);
+  }
+
+  /// A pipe's `(read, write)` ends.
+  fn pipe() -> (RawFd, RawFd) {
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    (fds[0], fds[1])
+  }
+
+  #[test]
+  fn select_prefers_earlier_receivers() {
+    let (tx1, rx1) = RingBuffer::channel(4, Overflow::Overwrite, 0);
+    let (tx2, rx2) = RingBuffer::channel(4, Overflow::Overwrite, 0);
+    let mut select = Select::new();
+    select.recv(&rx1).recv(&rx2);
+    assert!(select.select_some().is_none());
+
+    tx2.send(20);
+    tx1.send(10);
+    tx1.send(11);
+    let received: Vec<_> = std::iter::from_fn(|| match select.select_some()? {
+      Selected::Message(n) => Some(n),
+      Selected::Fd => panic!("no fd was registered"),
+    })
+    .collect();
+    assert_eq!(received, [10, 11, 20]);
+  }
+
+  #[test]
+  fn select_wakes_up_on_a_readable_fd() {
+    let (read, write) = pipe();
+    let (tx, rx) = RingBuffer::channel(4, Overflow::Overwrite, 0);
+
+    thread::scope(|s| {
+      let selecting = s.spawn(|| {
+        let mut select = Select::new();
+        select.recv(&rx).fd(read);
+        select.select()
+      });
+      // Neither the quiet pipe nor the empty receiver wake it up.
+      thread::sleep(std::time::Duration::from_millis(50));
+      assert!(!selecting.is_finished());
+
+      tx.send(1);
+      assert!(matches!(selecting.join().unwrap(), Selected::Message(1)));
+    });
+
+    assert_eq!(unsafe { libc::write(write, b"x".as_ptr().cast(), 1) }, 1);
+    let mut select = Select::new();
+    select.recv(&rx).fd(read);
+    assert!(matches!(select.select(), Selected::Fd));
+    // Messages still come first.
+    tx.send(2);
+    assert!(matches!(select.select(), Selected::Message(2)));
+
+    unsafe {
+      libc::close(read);
+      libc::close(write);
+    }
+  }
+
+  #[test]
+  fn lossy_receiver_doesnt_block() {
+    let (tx, rx) = RingBuffer::channel(2, Overflow::Block, 0);
+    let lossy = Receiver::lossy(&tx);
+    drop(rx);
+
+    (1..=5).for_each(|n| assert!(tx.try_send(n).is_ok()));
+    assert_eq!(std::iter::from_fn(|| lossy.recv_some()).collect::<Vec<_>>(), [4, 5]);
+    assert_eq!(lossy.dropped(), 3);
+  }
+
+  #[test]
+  fn block_stress_delivers_everything_in_order() {
+    let (tx, rx) = RingBuffer::channel(8, Overflow::Block, (0, 0));
+    let receivers: Vec<_> = (0..3)
+      .map(|_| {
+        let rx = rx.clone();
+        thread::spawn(move || {
+          let mut next = [0; SENDERS];
+          for _ in 0..SENDERS * MESSAGES {
+            let (sender, n) = rx.recv();
+            assert_eq!(n, next[sender], "message from sender {sender} out of order");
+            next[sender] += 1;
+          }
+        })
+      })
+      .collect();
+    drop(rx);
+
+    spawn_senders(&tx).into_iter().for_each(|h| h.join().unwrap());
+    receivers.into_iter().for_each(|h| h.join().unwrap());
+  }
+
+  #[test]
+  fn overwrite_stress_never_goes_backwards() {
+    const DONE: (usize, usize) = (usize::MAX, 0);
+
+    let (tx, rx) = RingBuffer::channel(8, Overflow::Overwrite, (0, 0));
+    let receivers: Vec<_> = (0..3)
+      .map(|_| {
+        let rx = rx.clone();
+        thread::spawn(move || {
+          let mut last: [Option<usize>; SENDERS] = [None; SENDERS];
+          loop {
+            let (sender, n) = rx.recv();
+            if (sender, n) == DONE {
+              break;
+            }
+
+            assert!(last[sender].is_none_or(|last| n > last), "message from sender {sender} seen out of order");
+            last[sender] = Some(n);
+          }
+        })
+      })
+      .collect();
+    drop(rx);
+
+    spawn_senders(&tx).into_iter().for_each(|h| h.join().unwrap());
+    tx.send(DONE);
+    receivers.into_iter().for_each(|h| h.join().unwrap());
+  }
+}
+
+/// Model checked with `RUSTFLAGS="--cfg loom" cargo test --release loom`.
+#[cfg(all(test, loom))]
+mod loom_tests {
+  use super::*;
+
+  fn model(f: impl Fn() + Sync + Send + 'static) {
+    let mut builder = loom::model::Builder::new();
+    builder.preemption_bound = Some(3);
+    builder.check(f);
+  }
+
+  #[test]
+  fn loom_concurrent_senders_block() {
+    model(|| {
+      let (tx, rx) = RingBuffer::channel(1, Overflow::Block, 0);
+
+      let a = {
+        let tx = tx.clone();
+        thread::spawn(move || tx.try_send(1).is_ok())
+      };
+      let b = thread::spawn(move || tx.try_send(2).is_ok());
+
+      let mut received: Vec<_> = rx.recv_some().into_iter().collect();
+      let sent: Vec<_> = [(1, a.join().unwrap()), (2, b.join().unwrap())]
+        .into_iter()
+        .filter_map(|(n, ok)| ok.then_some(n))
+        .collect();
+      received.extend(std::iter::from_fn(|| rx.recv_some()));
+
+      received.sort();
+      assert_eq!(received, sent, "accepted messages must never be overwritten");
+    });
+  }
+
+  #[test]
+  fn loom_concurrent_senders_overwrite() {
+    model(|| {
+      let (tx, rx) = RingBuffer::channel(2, Overflow::Overwrite, 0);
+
+      let a = {
+        let tx = tx.clone();
+        thread::spawn(move || {
+          tx.send(1);
+          tx.send(2);
+        })
+      };
+      let b = thread::spawn(move || tx.send(10));
+
+      let mut received = Vec::new();
+      while let Some(n) = rx.recv_some() {
+        received.push(n);
+      }
+
+      a.join().unwrap();
+      b.join().unwrap();
+
+      while let Some(n) = rx.recv_some() {
+        received.push(n);
+      }
+
+      let mut own = received.iter().filter(|&&n| n != 10);
+      assert!(!matches!((own.next(), own.next()), (Some(2), Some(1))));
+      assert!(received.windows(2).all(|w| w[0] != w[1]));
+      assert!(received.contains(&2) || received.contains(&10));
+    });
+  }
+}
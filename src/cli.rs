@@ -1,63 +1,1155 @@
+use crate::config::{self, Config, Value};
+use crate::dirwatch::Change;
 use crate::error::Error;
-use std::process::{Command, ExitStatus, Stdio};
-use std::{env, io::Write, path::PathBuf, str::FromStr};
+use crate::glob::Glob;
+use crate::log::{self, Format, Level};
+use crate::net;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// A command line option, `values` names what it takes (nothing for flags).
+struct Opt {
+  name: &'static str,
+  values: &'static [&'static str],
+  repeat: bool,
+  default: Option<&'static str>,
+  help: &'static str,
+}
+
+const fn opt(name: &'static str, values: &'static [&'static str], help: &'static str) -> Opt {
+  Opt {
+    name,
+    values,
+    repeat: false,
+    default: None,
+    help,
+  }
+}
+
+impl Opt {
+  const fn default(mut self, default: &'static str) -> Self {
+    self.default = Some(default);
+    self
+  }
+
+  const fn repeat(mut self) -> Self {
+    self.repeat = true;
+    self
+  }
+}
+
+const OPTIONS: &[Opt] = &[
+  opt("watch", &["<dir>"], "Directory to watch for changes, can be repeated")
+    .default(".")
+    .repeat(),
+  opt("serve", &["<dir>"], "Directory to serve").default("."),
+  opt("host", &["<addr>"], "Address to listen on, 0.0.0.0 or :: (IPv6 and IPv4) for every interface").default("127.0.0.1"),
+  opt("port", &["<port>"], "Port to listen to").default("8080"),
+  opt("no-serve", &[], "Only watch and run commands, without the HTTP server"),
+  opt("no-watch", &[], "Only serve files, without watching or running commands"),
+  opt("run", &["<cmd>"], "Command to run when files change, repeat it for a pipeline of steps").repeat(),
+  opt("after", &["<cmd>"], "Command to run once the browsers were told to reload"),
+  opt("on", &["<glob>", "<cmd>"], "Command to run when files matching <glob> change, can be repeated").repeat(),
+  opt("ignore", &["<glob>"], "Ignore the paths matching <glob>, can be repeated").repeat(),
+  opt("initial", &[], "Run the commands once at startup, before serving"),
+  opt("once", &[], "Run the commands once and exit with their status"),
+  opt("cwd", &["<dir>"], "Directory to run the commands in"),
+  opt("env", &["<KEY=VALUE>"], "Set an environment variable for the commands, can be repeated").repeat(),
+  opt("env-file", &["<file>"], "Load environment variables from <file>").default(".env in the command directory"),
+  opt("timeout", &["<secs>"], "Stop commands running longer than this"),
+  opt("cpu-limit", &["<secs>"], "Limit the CPU time of commands"),
+  opt("memory-limit", &["<MB>"], "Limit the memory of commands"),
+  opt("daemon", &["<cmd>"], "Long running process to restart after every successful build"),
+  opt("exec", &[], "Execute commands directly instead of through `sh -c`, with `{}` as the changed paths"),
+  opt("cancel", &[], "Restart the running command when files change"),
+  opt("null", &[], "Separate the paths written to the command's stdin with NUL instead of newline"),
+  opt("quiet", &[], "Only log warnings and errors"),
+  opt("verbose", &[], "Also log debugging details"),
+  opt("log-format", &["text|json"], "Log as colored text on a terminal, or one JSON object per line").default("text"),
+  opt("capacity", &["<events>"], "Size of the event buffer").default("32"),
+  opt("lossless", &[], "Make senders wait for slow consumers instead of skipping events"),
+  opt("record", &["<file>"], "Record events to <file>"),
+  opt("replay", &["<file>"], "Replay the file changes recorded in <file> instead of watching"),
+  opt("config", &["<file>"], "Config file to use instead of the nearest dirwatch.toml"),
+  opt("version", &[], "Print the version"),
+  opt("help", &[], "Print this help"),
+];
+
+/// The help text listing every option.
+pub fn help() -> String {
+  let mut help = String::from("Usage: dirwatch [options]\n\nOptions:\n");
+  for opt in OPTIONS {
+    let usage = format!("-{} {}", opt.name, opt.values.join(" "));
+    help.push_str(&format!("  {usage:<22} {}", opt.help));
+    if let Some(default) = opt.default {
+      help.push_str(&format!(" (default: {default})"));
+    }
+    help.push('\n');
+  }
+  help.push_str("\nOptions can also be written `--name value` or `--name=value`, flags accept `--name=false`.\n");
+  help
+}
+
+/// The options given on the command line, in order.
+pub struct Args {
+  given: Vec<(&'static str, Vec<String>)>,
+}
+
+impl Args {
+  pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, Error> {
+    let mut args = args.into_iter();
+    let mut given: Vec<(&'static str, Vec<String>)> = Vec::new();
+
+    while let Some(arg) = args.next() {
+      let Some(flag) = arg.strip_prefix("--").or_else(|| arg.strip_prefix('-')).filter(|f| !f.is_empty())
+      else {
+        return Err(Error::UnexpectedArg(arg));
+      };
+
+      let (name, inline) = match flag.split_once('=') {
+        Some((name, value)) => (name, Some(value.to_string())),
+        None => (flag, None),
+      };
+      let name = if name == "h" { "help" } else { name };
+
+      let Some(opt) = OPTIONS.iter().find(|o| o.name == name)
+      else {
+        return Err(Error::UnknownOption(arg.clone(), suggest(name)));
+      };
+      if !opt.repeat && given.iter().any(|(n, _)| *n == opt.name) {
+        return Err(Error::RepeatedOption(opt.name));
+      }
+
+      let mut values = Vec::from_iter(inline);
+      while values.len() < opt.values.len() {
+        values.push(args.next().ok_or(Error::MissingValue(opt.name))?);
+      }
+      given.push((opt.name, values));
+    }
+
+    Ok(Self { given })
+  }
+
+  pub fn has(&self, name: &str) -> bool {
+    self.given.iter().any(|(n, _)| *n == name)
+  }
+
+  fn all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a [String]> {
+    self.given.iter().filter(move |(n, _)| *n == name).map(|(_, values)| &values[..])
+  }
+
+  /// The value of a non repeatable option.
+  pub fn value<T: FromStr>(&self, name: &'static str) -> Result<Option<T>, Error> {
+    let Some(values) = self.all(name).next()
+    else {
+      return Ok(None);
+    };
+    values[0].parse().map(Some).map_err(|_| Error::InvalidValue(name, values[0].clone()))
+  }
+
+  /// Every value of a repeatable option.
+  pub fn values(&self, name: &str) -> Vec<String> {
+    self.all(name).map(|values| values[0].clone()).collect()
+  }
+
+  /// Every value pair of a repeatable option taking two values.
+  pub fn pairs(&self, name: &str) -> Vec<(String, String)> {
+    self.all(name).map(|values| (values[0].clone(), values[1].clone())).collect()
+  }
+
+  /// Whether a flag was given, `--flag=false` turns it off explicitly.
+  pub fn flag(&self, name: &'static str) -> Result<Option<bool>, Error> {
+    match self.all(name).next() {
+      None => Ok(None),
+      Some([]) => Ok(Some(true)),
+      Some([value, ..]) => value.parse().map(Some).map_err(|_| Error::InvalidValue(name, value.clone())),
+    }
+  }
+}
+
+/// The option closest to the misspelled `name`, if any is close enough.
+fn suggest(name: &str) -> Option<&'static str> {
+  let distance = |a: &str, b: &str| {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+      let mut prev = row[0];
+      row[0] = i + 1;
+      for (j, &cb) in b.iter().enumerate() {
+        let cost = if ca == cb { prev } else { prev + 1 };
+        prev = row[j + 1];
+        row[j + 1] = cost.min(row[j] + 1).min(prev + 1);
+      }
+    }
+    row[b.len()]
+  };
+
+  OPTIONS
+    .iter()
+    .map(|o| (distance(name, o.name), o.name))
+    .filter(|&(d, _)| d <= 2)
+    .min()
+    .map(|(_, name)| name)
+}
 
 pub struct Cli {
-  pub dir_watch: PathBuf,
+  pub config: Option<PathBuf>,
+  pub dir_watch: Vec<PathBuf>,
   pub dir_serve: PathBuf,
-  pub cmd: String,
-  pub port: String,
+  pub steps: Vec<Step>,
+  pub after: Option<String>,
+  pub cwd: Option<PathBuf>,
+  /// The `.env` file, then the config and then `-env`, later values win.
+  pub env: Vec<(String, String)>,
+  pub host: IpAddr,
+  pub port: u16,
+  pub no_serve: bool,
+  pub no_watch: bool,
+  pub capacity: usize,
+  pub lossless: bool,
+  pub record: Option<PathBuf>,
+  pub replay: Option<PathBuf>,
+  pub exec: bool,
+  pub cancel: bool,
+  pub limits: Limits,
+  pub daemon: Option<String>,
+  pub initial: bool,
+  pub once: bool,
+  pub null: bool,
+  pub rules: Vec<(String, String)>,
+  pub ignore: Vec<String>,
+  pub log_level: Level,
+  pub log_format: Format,
+  pub headers: Vec<(String, String)>,
+  pub proxies: Vec<(String, String)>,
 }
 
 impl Cli {
-  pub const USAGE: &str = "Usage: dirwatch -watch <dir> -serve <dir> -run <cmd> -port <port>";
+  /// Keys a `dirwatch.toml` may contain.
+  const CONFIG_KEYS: &[&str] = &[
+    "watch",
+    "serve",
+    "host",
+    "port",
+    "no-serve",
+    "no-watch",
+    "run",
+    "after",
+    "cwd",
+    "env-file",
+    "env.*",
+    "capacity",
+    "lossless",
+    "exec",
+    "cancel",
+    "timeout",
+    "cpu-limit",
+    "memory-limit",
+    "daemon",
+    "initial",
+    "once",
+    "null",
+    "ignore",
+    "quiet",
+    "verbose",
+    "log-format",
+    "on.*",
+    "headers.*",
+    "proxy.*",
+  ];
+
+  /// Reads `args` on top of `-config <file>`, or the `dirwatch.toml` found in the current or a
+  /// parent directory.
+  pub fn parse(args: &Args) -> Result<Self, Error> {
+    let config = match args.value::<PathBuf>("config")? {
+      Some(path) => Config::load(&path)?,
+      None => Config::find().map(|path| Config::load(&path)).transpose()?.unwrap_or_default(),
+    };
+    config.check(Self::CONFIG_KEYS)?;
+
+    let config_u64 = |name: &str| -> Result<Option<u64>, Error> {
+      config
+        .integer(name)?
+        .map(u64::try_from)
+        .transpose()
+        .map_err(|_| Error::ConfigValue(name.into()))
+    };
+    let limit = |name: &'static str, max: u64| -> Result<Option<u64>, Error> {
+      match args.value::<u64>(name)? {
+        Some(value) if value > max => Err(Error::InvalidValue(name, value.to_string())),
+        Some(value) => Ok(Some(value)),
+        None => match config_u64(name)? {
+          Some(value) if value > max => Err(Error::ConfigValue(name.into())),
+          value => Ok(value),
+        },
+      }
+    };
+    let flag = |name: &'static str| -> Result<bool, Error> { Ok(args.flag(name)?.or(config.boolean(name)?).unwrap_or(false)) };
+
+    // Rules given on the command line replace the ones for the same glob in the file.
+    let mut rules = config.table("on")?;
+    for (glob, cmd) in args.pairs("on") {
+      rules.retain(|(g, _)| *g != glob);
+      rules.push((glob, cmd));
+    }
+
+    // Steps given on the command line replace the whole pipeline.
+    let steps = match args.values("run") {
+      run if !run.is_empty() => run.iter().map(|run| Step::new(run)).collect(),
+      _ => match config.get("run") {
+        None => Vec::new(),
+        Some(Value::Array(steps)) => steps.iter().map(|step| Step::from_config(step, &config)).collect::<Result<_, _>>()?,
+        Some(step) => vec![Step::from_config(step, &config)?],
+      },
+    };
+
+    let cwd = args.value::<PathBuf>("cwd")?.or(config.path("cwd")?);
+    if let Some(ref cwd) = cwd {
+      if !cwd.is_dir() {
+        return Err(Error::InvalidValue("cwd", cwd.display().to_string()));
+      }
+    }
+
+    // A missing `.env` is only an error when it was asked for.
+    let mut env = match args.value::<PathBuf>("env-file")?.or(config.path("env-file")?) {
+      Some(path) => config::load_dotenv(&path)?,
+      None => {
+        let path = cwd.as_deref().unwrap_or(Path::new("")).join(".env");
+        if path.is_file() {
+          config::load_dotenv(&path)?
+        }
+        else {
+          Vec::new()
+        }
+      }
+    };
+    env.extend(config.table("env")?);
+    for var in args.values("env") {
+      match var.split_once('=') {
+        Some((name, value)) if !name.is_empty() => env.push((name.into(), value.into())),
+        _ => return Err(Error::InvalidValue("env", var)),
+      }
+    }
+
+    let watch = args.values("watch").into_iter().map(PathBuf::from).collect::<Vec<_>>();
+    let host = match args.value::<String>("host")? {
+      Some(host) => net::parse_host(&host).ok_or(Error::InvalidValue("host", host))?,
+      None => match config.string("host")? {
+        Some(host) => net::parse_host(&host).ok_or_else(|| Error::ConfigValue("host".into()))?,
+        None => IpAddr::V4(Ipv4Addr::LOCALHOST),
+      },
+    };
+    let port = match args.value("port")? {
+      Some(port) => port,
+      None => config
+        .integer("port")?
+        .map(u16::try_from)
+        .transpose()
+        .map_err(|_| Error::ConfigValue("port".into()))?
+        .unwrap_or(8080),
+    };
+    let capacity = match args.value("capacity")? {
+      Some(capacity) => capacity,
+      None => config
+        .integer("capacity")?
+        .map(usize::try_from)
+        .transpose()
+        .map_err(|_| Error::ConfigValue("capacity".into()))?
+        .unwrap_or(32),
+    };
+    if capacity == 0 {
+      return Err(Error::InvalidValue("capacity", "0".into()));
+    }
+    let log_level = match (flag("quiet")?, flag("verbose")?) {
+      (true, true) => return Err(Error::ConflictingOptions("quiet", "verbose")),
+      (true, false) => Level::Warn,
+      (false, true) => Level::Debug,
+      (false, false) => Level::Info,
+    };
+    let log_format = match args.value("log-format")? {
+      Some(format) => format,
+      None => match config.string("log-format")? {
+        Some(format) => format.parse().map_err(|_| Error::ConfigValue("log-format".into()))?,
+        None => Format::Text,
+      },
+    };
+    let (no_serve, no_watch) = (flag("no-serve")?, flag("no-watch")?);
+    if no_serve && no_watch {
+      return Err(Error::ConflictingOptions("no-serve", "no-watch"));
+    }
 
-  pub fn parse() -> Result<Self, Error> {
     Ok(Self {
-      dir_watch: find_arg("-watch").unwrap_or_else(|| ".".to_string()).into(),
-      dir_serve: find_arg("-serve").unwrap_or_else(|| ".".to_string()).into(),
-      port: find_arg("-port").unwrap_or_else(|| "8080".to_string()),
-      cmd: find_arg("-run").unwrap_or_else(|| "".to_string()),
+      dir_watch: Some(watch)
+        .filter(|dirs| !dirs.is_empty())
+        .or(config.paths("watch")?)
+        .filter(|dirs| !dirs.is_empty())
+        .unwrap_or_else(|| vec![".".into()]),
+      dir_serve: args.value("serve")?.or(config.path("serve")?).unwrap_or_else(|| ".".into()),
+      host,
+      port,
+      no_serve,
+      no_watch,
+      steps,
+      after: args.value("after")?.or(config.string("after")?),
+      cwd,
+      env,
+      capacity,
+      lossless: flag("lossless")?,
+      record: args.value("record")?,
+      replay: args.value("replay")?,
+      exec: flag("exec")?,
+      cancel: flag("cancel")?,
+      limits: Limits {
+        timeout: args.value("timeout")?.or(config_u64("timeout")?).map(Duration::from_secs),
+        cpu: limit("cpu-limit", Limits::MAX_CPU)?,
+        memory: limit("memory-limit", Limits::MAX_MEMORY)?,
+      },
+      daemon: args.value("daemon")?.or(config.string("daemon")?),
+      initial: flag("initial")?,
+      once: flag("once")?,
+      null: flag("null")?,
+      rules,
+      ignore: config
+        .strings("ignore")?
+        .unwrap_or_default()
+        .into_iter()
+        .chain(args.values("ignore"))
+        .collect(),
+      log_level,
+      log_format,
+      headers: config.table("headers")?,
+      proxies: config.table("proxy")?,
+      config: config.path,
     })
   }
 }
 
-pub fn find_flag(name: &str) -> bool {
-  env::args().any(|a| a == name)
+/// How long a stopped command gets to exit after SIGTERM before it's killed.
+pub const KILL_GRACE: Duration = Duration::from_secs(2);
+
+/// Constraints on each run of a command.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+  pub timeout: Option<Duration>,
+  /// CPU time in seconds.
+  pub cpu: Option<u64>,
+  /// Address space in megabytes.
+  pub memory: Option<u64>,
+}
+
+impl Limits {
+  /// The largest `cpu` whose hard limit, a second later, is still below `RLIM_INFINITY`.
+  pub const MAX_CPU: u64 = libc::RLIM_INFINITY - 2;
+  /// The largest `memory` whose size in bytes fits in an rlimit.
+  pub const MAX_MEMORY: u64 = libc::RLIM_INFINITY >> 20;
+
+  /// Runs in the child between fork and exec.
+  fn apply(&self) -> io::Result<()> {
+    // The hard limit is one second later so the command gets SIGXCPU rather than SIGKILL.
+    if let Some(cpu) = self.cpu {
+      let limit = libc::rlimit {
+        rlim_cur: cpu,
+        rlim_max: cpu.saturating_add(1),
+      };
+      if unsafe { libc::setrlimit(libc::RLIMIT_CPU, &limit) } != 0 {
+        return Err(io::Error::last_os_error());
+      }
+    }
+    if let Some(memory) = self.memory {
+      let bytes = memory.checked_mul(1024 * 1024).unwrap_or(libc::RLIM_INFINITY);
+      let limit = libc::rlimit {
+        rlim_cur: bytes,
+        rlim_max: bytes,
+      };
+      if unsafe { libc::setrlimit(libc::RLIMIT_AS, &limit) } != 0 {
+        return Err(io::Error::last_os_error());
+      }
+    }
+    Ok(())
+  }
+}
+
+/// A command of the `-run` pipeline.
+#[derive(Debug, Clone, Default)]
+pub struct Step {
+  pub run: String,
+  /// Directory to run in instead of the current one.
+  pub cwd: Option<PathBuf>,
+  /// Variables added to the environment.
+  pub env: Vec<(String, String)>,
+  /// Later steps still run when this one fails, and its failure doesn't fail the build.
+  pub keep_going: bool,
 }
 
-fn find_arg<F: FromStr>(arg_name: &str) -> Option<F> {
-  let mut args = env::args();
-  args
-    .position(|arg| arg == arg_name)
-    .and_then(|_| args.next())
-    .and_then(|n| n.parse::<F>().ok())
+impl Step {
+  pub fn new(run: &str) -> Self {
+    Self {
+      run: run.into(),
+      ..Self::default()
+    }
+  }
+
+  /// A step of `run` in the config file, either a command or an inline table with `run`, `cwd`,
+  /// `env` and `continue-on-error`.
+  fn from_config(value: &Value, config: &Config) -> Result<Self, Error> {
+    let entries = match value {
+      Value::String(run) => return Ok(Self::new(run)),
+      Value::Table(entries) => entries,
+      _ => return Err(Error::ConfigValue("run".into())),
+    };
+
+    let mut step = Self::default();
+    for (key, value) in entries {
+      match (key.as_str(), value) {
+        ("run", Value::String(run)) => step.run = run.clone(),
+        ("cwd", Value::String(cwd)) => step.cwd = Some(config.resolve(cwd)),
+        ("continue-on-error", Value::Boolean(keep_going)) => step.keep_going = *keep_going,
+        ("env", Value::Table(vars)) => {
+          for (name, value) in vars {
+            let Value::String(value) = value
+            else {
+              return Err(Error::ConfigValue(format!("run.env.{name}")));
+            };
+            step.env.push((name.clone(), value.clone()));
+          }
+        }
+        ("run" | "cwd" | "continue-on-error" | "env", _) => return Err(Error::ConfigValue(format!("run.{key}"))),
+        _ => return Err(Error::ConfigKey(format!("run.{key}"))),
+      }
+    }
+    Ok(step)
+  }
 }
 
-pub struct Cmd(Option<Command>);
+pub struct Cmd {
+  /// The steps with their command line, steps without a command are left out.
+  steps: Vec<(Step, Vec<String>)>,
+  exec: bool,
+  cwd: Option<PathBuf>,
+  env: Vec<(String, String)>,
+  root: Option<PathBuf>,
+  null: bool,
+  process_group: bool,
+  capture: bool,
+  limits: Limits,
+}
 
 impl Cmd {
-  pub fn new(cmd: &str) -> Self {
-    let mut cmd_iter = cmd.split_whitespace();
-    let Some(exe) = cmd_iter.next()
+  /// Runs `cmd` through `sh -c`, or when `exec` is set, executes it directly after splitting it into
+  /// words the way a POSIX shell would.
+  pub fn new(cmd: &str, exec: bool) -> Result<Self, Error> {
+    Self::pipeline(&[Step::new(cmd)], exec)
+  }
+
+  /// Runs `steps` one after the other like [`Cmd::new`], stopping at the first failing step unless
+  /// it keeps going.
+  pub fn pipeline(steps: &[Step], exec: bool) -> Result<Self, Error> {
+    let mut with_argv = Vec::new();
+    for step in steps {
+      let argv = if step.run.trim().is_empty() {
+        Vec::new()
+      }
+      else if exec {
+        shell_words(&step.run)?
+      }
+      else {
+        vec!["sh".into(), "-c".into(), step.run.clone()]
+      };
+
+      if !argv.is_empty() {
+        with_argv.push((step.clone(), argv));
+      }
+    }
+
+    Ok(Self {
+      steps: with_argv,
+      exec,
+      cwd: None,
+      env: Vec::new(),
+      root: None,
+      null: false,
+      process_group: false,
+      capture: true,
+      limits: Limits::default(),
+    })
+  }
+
+  /// Runs the steps without a `cwd` of their own in `dir`.
+  pub fn cwd(&mut self, dir: &Path) -> &mut Self {
+    self.cwd = Some(dir.to_path_buf());
+    self
+  }
+
+  /// Adds `vars` to the environment, the `DIRWATCH_*` variables and those of a step take precedence.
+  pub fn env(&mut self, vars: &[(String, String)]) -> &mut Self {
+    self.env.extend_from_slice(vars);
+    self
+  }
+
+  /// Passes the watched directory to the command as `DIRWATCH_ROOT`.
+  pub fn root(&mut self, root: &Path) -> &mut Self {
+    self.root = Some(root.to_path_buf());
+    self
+  }
+
+  /// Writes the changed paths to stdin separated by NUL instead of newline.
+  pub fn null(&mut self) -> &mut Self {
+    self.null = true;
+    self
+  }
+
+  /// Runs the command in its own process group so [`Running::terminate`] also stops anything it
+  /// spawned.
+  pub fn process_group(&mut self) -> &mut Self {
+    self.process_group = true;
+    self
+  }
+
+  /// Commands that time out are stopped along with their process group.
+  pub fn limits(&mut self, limits: Limits) -> &mut Self {
+    if limits.timeout.is_some() {
+      self.process_group = true;
+    }
+    self.limits = limits;
+    self
+  }
+
+  /// Only relay the output to the terminal without keeping it, for commands that run indefinitely.
+  pub fn no_capture(&mut self) -> &mut Self {
+    self.capture = false;
+    self
+  }
+
+  /// Builds the command line of `step` for `changes`, with `-exec` `{}` and `{path}` are replaced
+  /// with the changed paths: a word that is just a placeholder becomes one argument per path, and
+  /// they're joined with spaces elsewhere. Shell scripts are left alone, braces mean other things
+  /// there (`find -exec rm {} +`, awk).
+  fn command(&self, (step, argv): &(Step, Vec<String>), changes: &[Change]) -> Command {
+    let (exe, args) = argv.split_first().expect("steps without a command are left out");
+    let paths = changes.iter().map(|c| c.path.as_str()).collect::<Vec<_>>();
+
+    let mut cmd = Command::new(exe);
+    if self.exec {
+      for arg in args {
+        match arg.as_str() {
+          "{}" | "{path}" => cmd.args(&paths),
+          arg => cmd.arg(replace_placeholders(arg, &paths.join(" "))),
+        };
+      }
+    }
+    else {
+      cmd.args(args);
+    }
+
+    let mut kinds = Vec::new();
+    for change in changes {
+      if !kinds.contains(&change.kind.as_str()) {
+        kinds.push(change.kind.as_str());
+      }
+    }
+    cmd.envs(self.env.iter().map(|(k, v)| (k, v)));
+    let changed = paths.join("\n");
+    if changed.len() <= MAX_CHANGED_ENV {
+      cmd.env("DIRWATCH_CHANGED", changed);
+    }
+    cmd.env("DIRWATCH_EVENT", kinds.join(" "));
+    if let Some(ref root) = self.root {
+      cmd.env("DIRWATCH_ROOT", root);
+    }
+    cmd.envs(step.env.iter().map(|(k, v)| (k, v)));
+    if let Some(cwd) = step.cwd.as_ref().or(self.cwd.as_ref()) {
+      cmd.current_dir(cwd);
+    }
+    if self.process_group {
+      cmd.process_group(0);
+    }
+    if self.limits.cpu.is_some() || self.limits.memory.is_some() {
+      let limits = self.limits;
+      unsafe { cmd.pre_exec(move || limits.apply()) };
+    }
+
+    cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    cmd
+  }
+
+  /// Starts the first step for `changes`, returns `None` if there's no command to run.
+  ///
+  /// The changed paths are written to the stdin of every step, one per line (or NUL terminated),
+  /// and are also in `DIRWATCH_CHANGED` unless there are too many, with their kinds (`create`, `modify`, `delete`) in
+  /// `DIRWATCH_EVENT`.
+  pub fn spawn(&mut self, changes: &[Change]) -> Result<Option<Running>, Error> {
+    let mut queue = self
+      .steps
+      .iter()
+      .map(|step| Queued {
+        command: self.command(step, changes),
+        run: step.0.run.clone(),
+        keep_going: step.0.keep_going,
+      })
+      .collect::<VecDeque<_>>();
+    let Some(mut first) = queue.pop_front()
     else {
-      return Self(None);
+      return Ok(None);
     };
 
-    let mut cmd = Command::new(exe);
-    cmd.args(cmd_iter).stdin(Stdio::piped());
-    Self(Some(cmd))
+    let separator = if self.null { b'\0' } else { b'\n' };
+    let mut input = Vec::new();
+    for change in changes {
+      input.extend(change.path.as_bytes());
+      input.push(separator);
+    }
+
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let (child, tees) = launch(&mut first.command, &input, self.capture.then(|| output.clone()))?;
+
+    Ok(Some(Running {
+      child,
+      output,
+      tees,
+      exited: None,
+      process_group: self.process_group,
+      deadline: self.limits.timeout.and_then(|timeout| Instant::now().checked_add(timeout)),
+      limits: self.limits,
+      timed_out: false,
+      pipeline: !queue.is_empty(),
+      step: first,
+      queue,
+      input,
+      capture: self.capture,
+    }))
   }
+}
+
+/// The threads relaying a command's stdout and stderr.
+type Tees = Vec<JoinHandle<io::Result<()>>>;
 
-  pub fn run_wait(&mut self, data: &[u8]) -> Result<ExitStatus, Error> {
-    if let Some(ref mut cmd) = self.0 {
-      let mut p = cmd.stdout(Stdio::null()).spawn()?;
-      if let Some(stdin) = p.stdin.as_mut() {
-        stdin.write_all(data)?;
+/// The longest `DIRWATCH_CHANGED`, a single variable can't be over 128 KiB and the whole
+/// environment counts towards the limit of the command line.
+const MAX_CHANGED_ENV: usize = 64 * 1024;
+
+/// The status of a command that couldn't start, like the shell's "command not found".
+pub fn not_started() -> ExitStatus {
+  ExitStatus::from_raw(127 << 8)
+}
+
+/// How long the output is still read after a command exited, a process it left running in the
+/// background can keep the pipes open for much longer.
+const TEE_GRACE: Duration = Duration::from_millis(200);
+
+/// Spawns `cmd` with `input` on its stdin, its output is relayed to the terminal and appended to
+/// `output` if there's one (as long as it's alive).
+fn launch(cmd: &mut Command, input: &[u8], output: Option<Arc<Mutex<Vec<u8>>>>) -> Result<(Child, Tees), Error> {
+  let mut child = cmd.spawn()?;
+
+  let output = output.as_ref().map(Arc::downgrade);
+  let mut tees = Vec::new();
+  if let Some(stdout) = child.stdout.take() {
+    let output = output.clone();
+    tees.push(thread::spawn(move || tee(stdout, log::output(false), output)));
+  }
+  if let Some(stderr) = child.stderr.take() {
+    tees.push(thread::spawn(move || tee(stderr, log::output(true), output)));
+  }
+
+  // Written from its own thread once the output is read, so a command that writes a lot before
+  // reading its input doesn't block either side. Commands that exit without reading it are fine.
+  if let Some(mut stdin) = child.stdin.take() {
+    let input = input.to_vec();
+    thread::spawn(move || {
+      if let Err(e) = stdin.write_all(&input) {
+        if e.kind() != ErrorKind::BrokenPipe {
+          log::debug("Couldn't write the changes to stdin", e);
+        }
+      }
+    });
+  }
+  Ok((child, tees))
+}
+
+/// A step of a pipeline that's ready to start.
+struct Queued {
+  command: Command,
+  run: String,
+  keep_going: bool,
+}
+
+/// A command started with [`Cmd::spawn`].
+pub struct Running {
+  child: Child,
+  output: Arc<Mutex<Vec<u8>>>,
+  tees: Tees,
+  /// When the current step exited, while its tees finish reading.
+  exited: Option<Instant>,
+  process_group: bool,
+  deadline: Option<Instant>,
+  limits: Limits,
+  timed_out: bool,
+  /// Whether there's more than one step, single commands don't report their steps.
+  pipeline: bool,
+  step: Queued,
+  queue: VecDeque<Queued>,
+  input: Vec<u8>,
+  capture: bool,
+}
+
+impl Running {
+  /// Returns the exit status and output once the last step finished, a command past its timeout is
+  /// stopped.
+  pub fn try_wait(&mut self) -> Result<Option<(ExitStatus, Vec<u8>)>, Error> {
+    loop {
+      let status = match self.child.try_wait()? {
+        Some(status) => status,
+        None if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+          self.timed_out = true;
+          self.stop(KILL_GRACE)?
+        }
+        None => return Ok(None),
+      };
+
+      if !self.tees_done()? {
+        return Ok(None);
+      }
+      if let Some(note) = self.limit_note(status) {
+        self.note(&note, "");
+      }
+      if self.timed_out || !self.step_finished(status) {
+        return Ok(Some((status, self.take_output())));
+      }
+
+      match self.queue.pop_front() {
+        Some(mut step) => {
+          let output = self.capture.then(|| self.output.clone());
+          match launch(&mut step.command, &self.input, output) {
+            Ok(launched) => (self.child, self.tees) = launched,
+            Err(e) => {
+              self.note("Couldn't start step", &format!("{} ({e})", step.run));
+              return Ok(Some((not_started(), self.take_output())));
+            }
+          }
+          self.step = step;
+        }
+        // The last step may have failed without failing the build.
+        None => return Ok(Some((ExitStatus::default(), self.take_output()))),
+      }
+    }
+  }
+
+  /// Sends SIGTERM and falls back to SIGKILL if the command is still running after `grace`, the
+  /// steps that didn't start yet never will.
+  pub fn terminate(mut self, grace: Duration) -> Result<(), Error> {
+    self.stop(grace)?;
+    while !self.tees_done()? {
+      thread::sleep(Duration::from_millis(10));
+    }
+    Ok(())
+  }
+
+  fn stop(&mut self, grace: Duration) -> Result<ExitStatus, Error> {
+    self.signal(libc::SIGTERM);
+
+    let deadline = Instant::now() + grace;
+    while Instant::now() < deadline {
+      if let Some(status) = self.child.try_wait()? {
+        return Ok(status);
+      }
+      thread::sleep(Duration::from_millis(10));
+    }
+
+    self.signal(libc::SIGKILL);
+    Ok(self.child.wait()?)
+  }
+
+  /// Joins the tees once they read everything, returns false while they still have time to. The
+  /// ones still reading after `TEE_GRACE` are detached.
+  fn tees_done(&mut self) -> Result<bool, Error> {
+    let exited = *self.exited.get_or_insert_with(Instant::now);
+    if self.tees.iter().any(|tee| !tee.is_finished()) && exited.elapsed() < TEE_GRACE {
+      return Ok(false);
+    }
+
+    self.exited = None;
+    for tee in self.tees.drain(..) {
+      if tee.is_finished() {
+        tee.join().unwrap()?;
+      }
+    }
+    Ok(true)
+  }
+
+  /// Explains a failure caused by the timeout or a resource limit.
+  fn limit_note(&self, status: ExitStatus) -> Option<String> {
+    if self.timed_out {
+      return Some(format!("Timed out after {:?}", self.limits.timeout?));
+    }
+
+    let signal = status.signal()?;
+    if let (libc::SIGXCPU, Some(cpu)) = (signal, self.limits.cpu) {
+      return Some(format!("Exceeded the CPU time limit of {cpu}s"));
+    }
+    if let (libc::SIGKILL | libc::SIGSEGV | libc::SIGABRT | libc::SIGBUS, Some(memory)) = (signal, self.limits.memory) {
+      return Some(format!("Killed by {signal}, it may have run out of its {memory} MB memory limit"));
+    }
+    None
+  }
+
+  /// Reports how a step of a pipeline ended, returns whether the next one should run.
+  fn step_finished(&self, status: ExitStatus) -> bool {
+    let run = &self.step.run;
+    if status.success() {
+      if self.pipeline {
+        log::info("Step done", run);
+      }
+      true
+    }
+    else if self.step.keep_going {
+      self.note("Step failed", &format!("{run} ({status}), continuing"));
+      true
+    }
+    else {
+      if self.pipeline {
+        self.note("Step failed", &format!("{run} ({status})"));
       }
-      return Ok(p.wait()?);
+      false
     }
+  }
+
+  /// Logs a warning and adds it to the output so the browser overlay shows it too.
+  fn note(&self, title: &str, detail: &str) {
+    log::warn(title, detail);
+    if self.capture {
+      let note = if detail.is_empty() {
+        format!("{title}\n")
+      }
+      else {
+        format!("{title}: {detail}\n")
+      };
+      self.output.lock().unwrap().extend(note.into_bytes());
+    }
+  }
+
+  fn take_output(&mut self) -> Vec<u8> {
+    std::mem::take(&mut *self.output.lock().unwrap())
+  }
+
+  fn signal(&self, signal: i32) {
+    let pid = self.child.id() as i32;
+    let pid = if self.process_group { -pid } else { pid };
+    unsafe { libc::kill(pid, signal) };
+  }
+}
+
+/// A command that only runs for the changes matching its glob, or for every change without one.
+pub struct Rule {
+  pub glob: Option<Glob>,
+  pub cmd: Cmd,
+}
+
+impl Rule {
+  pub fn matching(&self, changes: &[Change]) -> Vec<Change> {
+    let matches = |c: &&Change| self.glob.as_ref().is_none_or(|g| g.matches(c.path.as_str()));
+    changes.iter().filter(matches).copied().collect()
+  }
+}
+
+/// Keeps a long running command (like a backend server) alive across restarts.
+pub struct Daemon {
+  cmd: Cmd,
+  running: Option<Running>,
+}
+
+impl Daemon {
+  pub fn new(mut cmd: Cmd) -> Self {
+    cmd.process_group().no_capture();
+    Self { cmd, running: None }
+  }
+
+  pub fn start(&mut self) -> Result<(), Error> {
+    self.running = self.cmd.spawn(&[])?;
+    Ok(())
+  }
+
+  /// Stops the daemon giving it `grace` time to exit after SIGTERM.
+  pub fn stop(&mut self, grace: Duration) -> Result<(), Error> {
+    if let Some(running) = self.running.take() {
+      running.terminate(grace)?;
+    }
+    Ok(())
+  }
+
+  pub fn restart(&mut self, grace: Duration) -> Result<(), Error> {
+    self.stop(grace)?;
+    self.start()
+  }
+
+  /// Returns the exit status if the daemon exited on its own since the last check.
+  pub fn check(&mut self) -> Result<Option<ExitStatus>, Error> {
+    let Some(ref mut running) = self.running
+    else {
+      return Ok(None);
+    };
+
+    let status = running.try_wait()?.map(|(status, _)| status);
+    if status.is_some() {
+      self.running = None;
+    }
+    Ok(status)
+  }
+}
+
+/// Copies everything from `src` into `dst` as it comes and appends it to `log` if there's one.
+fn tee(mut src: impl Read, mut dst: impl Write, log: Option<Weak<Mutex<Vec<u8>>>>) -> io::Result<()> {
+  let mut buf = [0; 4096];
+  loop {
+    let n = src.read(&mut buf)?;
+    if n == 0 {
+      return Ok(());
+    }
+
+    dst.write_all(&buf[..n])?;
+    dst.flush()?;
+    if let Some(log) = log.as_ref().and_then(Weak::upgrade) {
+      log.lock().unwrap().extend_from_slice(&buf[..n]);
+    }
+  }
+}
+
+fn replace_placeholders(arg: &str, paths: &str) -> String {
+  arg.replace("{path}", paths).replace("{}", paths)
+}
+
+/// Splits `s` into words following POSIX shell quoting rules (no expansions).
+pub fn shell_words(s: &str) -> Result<Vec<String>, Error> {
+  let mut words = Vec::new();
+  let mut word: Option<String> = None;
+  let mut chars = s.chars();
+
+  while let Some(c) = chars.next() {
+    match c {
+      c if c.is_whitespace() => words.extend(word.take()),
+      '\\' => {
+        if let Some(c) = chars.next().filter(|&c| c != '\n') {
+          word.get_or_insert_with(String::new).push(c);
+        }
+      }
+      '\'' => {
+        let word = word.get_or_insert_with(String::new);
+        loop {
+          match chars.next().ok_or(Error::UnterminatedQuote)? {
+            '\'' => break,
+            c => word.push(c),
+          }
+        }
+      }
+      '"' => {
+        let word = word.get_or_insert_with(String::new);
+        loop {
+          match chars.next().ok_or(Error::UnterminatedQuote)? {
+            '"' => break,
+            '\\' => match chars.next().ok_or(Error::UnterminatedQuote)? {
+              '\n' => (),
+              c @ ('$' | '`' | '"' | '\\') => word.push(c),
+              c => word.extend(['\\', c]),
+            },
+            c => word.push(c),
+          }
+        }
+      }
+      c => word.get_or_insert_with(String::new).push(c),
+    }
+  }
+
+  words.extend(word);
+  Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::dirwatch::{ChangeKind, PascalString};
+
+  fn args(args: &[&str]) -> Result<Args, Error> {
+    Args::parse(args.iter().map(|a| a.to_string()))
+  }
+
+  #[test]
+  fn args_accept_every_form() {
+    let args = args(&[
+      "-port",
+      "3000",
+      "--serve",
+      "dist",
+      "--watch=src",
+      "-watch",
+      "assets",
+      "--on=*.ts",
+      "tsc",
+      "--cancel=false",
+      "-exec",
+    ])
+    .unwrap();
+    assert_eq!(args.value::<u16>("port").unwrap(), Some(3000));
+    assert_eq!(args.value::<String>("serve").unwrap().as_deref(), Some("dist"));
+    assert_eq!(args.values("watch"), ["src", "assets"]);
+    assert_eq!(args.pairs("on"), [("*.ts".to_string(), "tsc".to_string())]);
+    assert_eq!(args.flag("cancel").unwrap(), Some(false));
+    assert_eq!(args.flag("exec").unwrap(), Some(true));
+    assert_eq!(args.flag("null").unwrap(), None);
+  }
+
+  #[test]
+  fn args_report_mistakes() {
+    let err = |a: &[&str]| args(a).err().map(|e| e.to_string());
+    assert_eq!(err(&["-prot", "3000"]).unwrap(), "Unknown option `-prot`, did you mean `-port`?");
+    assert_eq!(err(&["-port"]).unwrap(), "Missing value for `-port`");
+    assert_eq!(err(&["-port", "1", "-port", "2"]).unwrap(), "`-port` can only be given once");
+    assert_eq!(err(&["dist"]).unwrap(), "Unexpected argument `dist`, see `dirwatch --help`");
+    assert!(err(&["-watch", "a", "-watch", "b"]).is_none());
+
+    let args = args(&["-port", "http"]).unwrap();
+    assert_eq!(args.value::<u16>("port").err().unwrap().to_string(), "Invalid value `http` for `-port`");
+  }
+
+  #[test]
+  fn shell_words_follow_posix_quoting() {
+    let words = shell_words(r#"esbuild 'src/my app.ts' --define:X="a \"b\" \$c \d" a\ b '' -x"#).unwrap();
+    assert_eq!(words, ["esbuild", "src/my app.ts", r#"--define:X=a "b" $c \d"#, "a b", "", "-x"]);
+  }
+
+  #[test]
+  fn placeholders_expand_to_changed_paths() {
+    let changes = ["a b.ts", "c.ts"].map(|p| Change {
+      path: PascalString::new(p.as_bytes()),
+      kind: ChangeKind::Modify,
+    });
+
+    let cmd = Cmd::new("esbuild {} --outdir=out/{path}", true).unwrap();
+    let args = cmd
+      .command(&cmd.steps[0], &changes)
+      .get_args()
+      .map(|a| a.to_str().unwrap().to_owned())
+      .collect::<Vec<_>>();
+    assert_eq!(args, ["a b.ts", "c.ts", "--outdir=out/a b.ts c.ts"]);
+
+    let cmd = Cmd::new("cat {}", false).unwrap();
+    let args = cmd
+      .command(&cmd.steps[0], &changes)
+      .get_args()
+      .map(|a| a.to_str().unwrap().to_owned())
+      .collect::<Vec<_>>();
+    assert_eq!(args, ["-c", "cat {}"]);
+  }
 
-    Ok(ExitStatus::default())
+  #[test]
+  fn shell_words_reject_unterminated_quotes() {
+    assert!(shell_words("echo 'oops").is_err());
+    assert!(shell_words("echo \"oops").is_err());
   }
 }
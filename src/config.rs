@@ -0,0 +1,486 @@
+use crate::error::Error;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+pub const FILE_NAME: &str = "dirwatch.toml";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+  String(String),
+  Integer(i64),
+  Boolean(bool),
+  Array(Vec<Value>),
+  /// An inline table, `{ key = value, ... }`.
+  Table(Vec<(String, Value)>),
+}
+
+/// The `(table, key, value)` of every entry in file order, top level keys have an empty table.
+type Entries = Vec<(String, String, Value)>;
+
+/// A `dirwatch.toml` file, written in the subset of TOML the options need: tables, strings,
+/// integers, booleans, arrays and inline tables.
+#[derive(Default)]
+pub struct Config {
+  pub path: Option<PathBuf>,
+  entries: Entries,
+}
+
+impl Config {
+  /// Looks for `dirwatch.toml` in the current directory and then in its parents.
+  pub fn find() -> Option<PathBuf> {
+    let cwd = env::current_dir().ok()?;
+    cwd.ancestors().map(|dir| dir.join(FILE_NAME)).find(|path| path.is_file())
+  }
+
+  pub fn load(path: &Path) -> Result<Self, Error> {
+    let text = fs::read_to_string(path).map_err(|e| Error::ConfigRead(path.to_path_buf(), e))?;
+    let entries = parse(&text).map_err(|(line, msg)| Error::ConfigParse(path.to_path_buf(), line, msg))?;
+
+    Ok(Self {
+      path: Some(path.to_path_buf()),
+      entries,
+    })
+  }
+
+  /// Fails on the first key that isn't in `known`, keys in tables are written `table.key` and
+  /// `table.*` accepts any key in that table.
+  pub fn check(&self, known: &[&str]) -> Result<(), Error> {
+    for (table, key, _) in &self.entries {
+      let name = full_key(table, key);
+      if !known.contains(&name.as_str()) && !known.contains(&format!("{table}.*").as_str()) {
+        return Err(Error::ConfigKey(name));
+      }
+    }
+    Ok(())
+  }
+
+  pub fn get(&self, key: &str) -> Option<&Value> {
+    self.entries.iter().find(|(t, k, _)| t.is_empty() && k == key).map(|(_, _, v)| v)
+  }
+
+  pub fn string(&self, key: &str) -> Result<Option<String>, Error> {
+    match self.get(key) {
+      None => Ok(None),
+      Some(Value::String(s)) => Ok(Some(s.clone())),
+      Some(_) => Err(Error::ConfigValue(key.into())),
+    }
+  }
+
+  pub fn integer(&self, key: &str) -> Result<Option<i64>, Error> {
+    match self.get(key) {
+      None => Ok(None),
+      Some(Value::Integer(n)) => Ok(Some(*n)),
+      Some(_) => Err(Error::ConfigValue(key.into())),
+    }
+  }
+
+  pub fn boolean(&self, key: &str) -> Result<Option<bool>, Error> {
+    match self.get(key) {
+      None => Ok(None),
+      Some(Value::Boolean(b)) => Ok(Some(*b)),
+      Some(_) => Err(Error::ConfigValue(key.into())),
+    }
+  }
+
+  /// A string or an array of strings.
+  pub fn strings(&self, key: &str) -> Result<Option<Vec<String>>, Error> {
+    match self.get(key) {
+      None => Ok(None),
+      Some(Value::String(s)) => Ok(Some(vec![s.clone()])),
+      Some(Value::Array(values)) => values
+        .iter()
+        .map(|v| match v {
+          Value::String(s) => Ok(s.clone()),
+          _ => Err(Error::ConfigValue(key.into())),
+        })
+        .collect::<Result<_, _>>()
+        .map(Some),
+      Some(_) => Err(Error::ConfigValue(key.into())),
+    }
+  }
+
+  /// Resolves a relative path against the config file's directory.
+  pub fn resolve(&self, path: &str) -> PathBuf {
+    self.path.as_deref().and_then(Path::parent).unwrap_or(Path::new("")).join(path)
+  }
+
+  /// A string holding a path relative to the config file's directory.
+  pub fn path(&self, key: &str) -> Result<Option<PathBuf>, Error> {
+    Ok(self.string(key)?.map(|path| self.resolve(&path)))
+  }
+
+  /// Like [`Config::strings`] with relative paths resolved against the config file's directory.
+  pub fn paths(&self, key: &str) -> Result<Option<Vec<PathBuf>>, Error> {
+    Ok(self.strings(key)?.map(|paths| paths.iter().map(|p| self.resolve(p)).collect()))
+  }
+
+  /// Every key in `[table]` with its string value, in file order.
+  pub fn table(&self, table: &str) -> Result<Vec<(String, String)>, Error> {
+    let entries = self.entries.iter().filter(|(t, _, _)| t == table);
+    entries
+      .map(|(t, k, v)| match v {
+        Value::String(s) => Ok((k.clone(), s.clone())),
+        _ => Err(Error::ConfigValue(full_key(t, k))),
+      })
+      .collect()
+  }
+}
+
+/// Reads the `KEY=VALUE` lines of a `.env` file, see [`parse_dotenv`].
+pub fn load_dotenv(path: &Path) -> Result<Vec<(String, String)>, Error> {
+  let text = fs::read_to_string(path).map_err(|e| Error::ConfigRead(path.to_path_buf(), e))?;
+  parse_dotenv(&text).map_err(|(line, msg)| Error::ConfigParse(path.to_path_buf(), line, msg))
+}
+
+/// Values can be single quoted as they are, or double quoted where `\n` is a newline. Lines can
+/// start with `export` and comments start with `#`.
+fn parse_dotenv(text: &str) -> Result<Vec<(String, String)>, (usize, &'static str)> {
+  let mut vars = Vec::new();
+  for (i, line) in text.lines().enumerate() {
+    let err = |msg| (i + 1, msg);
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let (name, value) = line.split_once('=').ok_or(err("expected `KEY=VALUE`"))?;
+    let name = name.trim();
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+      return Err(err("invalid variable name"));
+    }
+
+    let value = value.trim();
+    let value = match value.chars().next() {
+      Some(quote @ ('"' | '\'')) => {
+        let rest = &value[1..];
+        let end = rest.find(quote).ok_or(err("unterminated string"))?;
+        let after = rest[end + 1..].trim_start();
+        if !after.is_empty() && !after.starts_with('#') {
+          return Err(err("expected the end of the line"));
+        }
+        if quote == '"' {
+          rest[..end].replace("\\n", "\n")
+        }
+        else {
+          rest[..end].to_string()
+        }
+      }
+      _ => value.split(" #").next().unwrap_or_default().trim_end().to_string(),
+    };
+    vars.push((name.to_string(), value));
+  }
+  Ok(vars)
+}
+
+fn full_key(table: &str, key: &str) -> String {
+  if table.is_empty() {
+    key.to_string()
+  }
+  else {
+    format!("{table}.{key}")
+  }
+}
+
+/// Parses the supported TOML subset, errors are the line number and what went wrong.
+fn parse(text: &str) -> Result<Entries, (usize, &'static str)> {
+  let mut parser = Parser { text, pos: 0, line: 1 };
+  parser.document().map_err(|msg| (parser.line, msg))
+}
+
+struct Parser<'a> {
+  text: &'a str,
+  pos: usize,
+  line: usize,
+}
+
+impl Parser<'_> {
+  fn peek(&self) -> Option<char> {
+    self.text[self.pos..].chars().next()
+  }
+
+  fn next(&mut self) -> Option<char> {
+    let c = self.peek()?;
+    self.pos += c.len_utf8();
+    if c == '\n' {
+      self.line += 1;
+    }
+    Some(c)
+  }
+
+  fn expect(&mut self, expected: char, msg: &'static str) -> Result<(), &'static str> {
+    match self.next() {
+      Some(c) if c == expected => Ok(()),
+      _ => Err(msg),
+    }
+  }
+
+  fn skip_spaces(&mut self) {
+    while matches!(self.peek(), Some(' ' | '\t')) {
+      self.next();
+    }
+  }
+
+  /// Skips whitespace, newlines and comments.
+  fn skip_blank(&mut self) {
+    loop {
+      match self.peek() {
+        Some(' ' | '\t' | '\r' | '\n') => {
+          self.next();
+        }
+        Some('#') => {
+          while !matches!(self.peek(), None | Some('\n')) {
+            self.next();
+          }
+        }
+        _ => return,
+      }
+    }
+  }
+
+  /// Allows trailing spaces and a comment before the line ends.
+  fn end_of_line(&mut self) -> Result<(), &'static str> {
+    self.skip_spaces();
+    if self.peek() == Some('#') {
+      while !matches!(self.peek(), None | Some('\n')) {
+        self.next();
+      }
+    }
+    if self.peek() == Some('\r') {
+      self.next();
+    }
+    match self.peek() {
+      None | Some('\n') => Ok(()),
+      _ => Err("expected the end of the line"),
+    }
+  }
+
+  fn document(&mut self) -> Result<Entries, &'static str> {
+    let mut entries: Entries = Vec::new();
+    let mut table = String::new();
+
+    loop {
+      self.skip_blank();
+      match self.peek() {
+        None => return Ok(entries),
+        Some('[') => {
+          self.next();
+          self.skip_spaces();
+          table = self.key()?;
+          self.skip_spaces();
+          self.expect(']', "expected `]` after the table name")?;
+          if entries.iter().any(|(t, _, _)| *t == table) {
+            return Err("duplicate table");
+          }
+        }
+        Some(_) => {
+          let key = self.key()?;
+          self.skip_spaces();
+          self.expect('=', "expected `=` after the key")?;
+          self.skip_spaces();
+          let value = self.value()?;
+          if entries.iter().any(|(t, k, _)| *t == table && *k == key) {
+            return Err("duplicate key");
+          }
+          entries.push((table.clone(), key, value));
+        }
+      }
+      self.end_of_line()?;
+    }
+  }
+
+  fn key(&mut self) -> Result<String, &'static str> {
+    match self.peek() {
+      Some('"') => self.basic_string(),
+      Some('\'') => self.literal_string(),
+      _ => {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+          self.next();
+        }
+        if self.pos == start {
+          return Err("expected a key");
+        }
+        Ok(self.text[start..self.pos].to_string())
+      }
+    }
+  }
+
+  fn value(&mut self) -> Result<Value, &'static str> {
+    match self.peek() {
+      Some('"') => self.basic_string().map(Value::String),
+      Some('\'') => self.literal_string().map(Value::String),
+      Some('[') => {
+        self.next();
+        let mut values = Vec::new();
+        loop {
+          self.skip_blank();
+          if self.peek() == Some(']') {
+            self.next();
+            return Ok(Value::Array(values));
+          }
+
+          values.push(self.value()?);
+          self.skip_blank();
+          match self.next() {
+            Some(',') => (),
+            Some(']') => return Ok(Value::Array(values)),
+            _ => return Err("expected `,` or `]` in the array"),
+          }
+        }
+      }
+      Some('{') => {
+        self.next();
+        let mut entries: Vec<(String, Value)> = Vec::new();
+        loop {
+          self.skip_spaces();
+          if entries.is_empty() && self.peek() == Some('}') {
+            self.next();
+            return Ok(Value::Table(entries));
+          }
+
+          let key = self.key()?;
+          self.skip_spaces();
+          self.expect('=', "expected `=` after the key")?;
+          self.skip_spaces();
+          let value = self.value()?;
+          if entries.iter().any(|(k, _)| *k == key) {
+            return Err("duplicate key");
+          }
+          entries.push((key, value));
+
+          self.skip_spaces();
+          match self.peek() {
+            Some(',') => self.next(),
+            Some('}') => {
+              self.next();
+              return Ok(Value::Table(entries));
+            }
+            _ => return Err("expected `,` or `}` in the inline table"),
+          };
+        }
+      }
+      _ => {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '+') {
+          self.next();
+        }
+        match &self.text[start..self.pos] {
+          "true" => Ok(Value::Boolean(true)),
+          "false" => Ok(Value::Boolean(false)),
+          word => word.replace('_', "").parse().map(Value::Integer).map_err(|_| "expected a value"),
+        }
+      }
+    }
+  }
+
+  fn basic_string(&mut self) -> Result<String, &'static str> {
+    self.next();
+    let mut s = String::new();
+    loop {
+      match self.next_in_string()? {
+        '"' => return Ok(s),
+        '\\' => match self.next_in_string()? {
+          'n' => s.push('\n'),
+          't' => s.push('\t'),
+          'r' => s.push('\r'),
+          '"' => s.push('"'),
+          '\\' => s.push('\\'),
+          'u' => {
+            let hex = self.text.get(self.pos..self.pos + 4).ok_or("invalid unicode escape")?;
+            let c = u32::from_str_radix(hex, 16)
+              .ok()
+              .and_then(char::from_u32)
+              .ok_or("invalid unicode escape")?;
+            self.pos += 4;
+            s.push(c);
+          }
+          _ => return Err("invalid escape"),
+        },
+        c => s.push(c),
+      }
+    }
+  }
+
+  fn literal_string(&mut self) -> Result<String, &'static str> {
+    self.next();
+    let start = self.pos;
+    loop {
+      if self.next_in_string()? == '\'' {
+        return Ok(self.text[start..self.pos - 1].to_string());
+      }
+    }
+  }
+
+  /// Strings end on the line they started.
+  fn next_in_string(&mut self) -> Result<char, &'static str> {
+    match self.peek() {
+      None | Some('\n') => Err("unterminated string"),
+      Some(_) => Ok(self.next().unwrap()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_the_supported_subset() {
+    let text = r#"
+# Project settings
+watch = ["src", 'assets'] # trailing comment
+port = 8_080
+cancel = true
+run = "npm run build -- --define:X=\"1\""
+
+[on]
+"*.scss" = 'sass src/main.scss dist/main.css'
+"*.{ts,tsx}" = "esbuild src/main.ts"
+
+[headers]
+cache-control = "no-store"
+"#;
+
+    let entries = parse(text).unwrap();
+    let strings = |v: &[&str]| Value::Array(v.iter().map(|s| Value::String(s.to_string())).collect());
+    assert_eq!(entries[0], ("".into(), "watch".into(), strings(&["src", "assets"])));
+    assert_eq!(entries[1].2, Value::Integer(8080));
+    assert_eq!(entries[2].2, Value::Boolean(true));
+    assert_eq!(entries[3].2, Value::String(r#"npm run build -- --define:X="1""#.into()));
+    assert_eq!(entries[4], ("on".into(), "*.scss".into(), Value::String("sass src/main.scss dist/main.css".into())));
+    assert_eq!(entries[6], ("headers".into(), "cache-control".into(), Value::String("no-store".into())));
+  }
+
+  #[test]
+  fn parses_inline_tables() {
+    let text = "run = [\n  { run = 'lint', continue-on-error = true },\n  { env = { A = \"1\" } },\n]";
+    let entries = parse(text).unwrap();
+    let table = |e: &[(&str, Value)]| Value::Table(e.iter().map(|(k, v)| (k.to_string(), v.clone())).collect());
+    let steps = Value::Array(vec![
+      table(&[("run", Value::String("lint".into())), ("continue-on-error", Value::Boolean(true))]),
+      table(&[("env", table(&[("A", Value::String("1".into()))]))]),
+    ]);
+    assert_eq!(entries[0].2, steps);
+    assert_eq!(parse("env = { A = '1'\n}"), Err((1, "expected `,` or `}` in the inline table")));
+  }
+
+  #[test]
+  fn parses_dotenv_files() {
+    let text = "# comment\nA=1\nexport B = two words # note\nC='$D # kept'\nE=\"x\\ny\"\n\nF=\n";
+    let vars = parse_dotenv(text).unwrap();
+    let expected = [("A", "1"), ("B", "two words"), ("C", "$D # kept"), ("E", "x\ny"), ("F", "")];
+    assert_eq!(vars, expected.map(|(k, v)| (k.to_string(), v.to_string())));
+    assert_eq!(parse_dotenv("A=1\nB"), Err((2, "expected `KEY=VALUE`")));
+    assert_eq!(parse_dotenv("A='1"), Err((1, "unterminated string")));
+  }
+
+  #[test]
+  fn reports_the_line_of_errors() {
+    assert_eq!(parse("port = 1\nport = 2"), Err((2, "duplicate key")));
+    assert_eq!(parse("\n\nrun = \"oops"), Err((3, "unterminated string")));
+    assert_eq!(parse("run = 'oops\nport = 1"), Err((1, "unterminated string")));
+    assert_eq!(parse("watch = [\n  \"a\"\n  \"b\"\n]"), Err((3, "expected `,` or `]` in the array")));
+    assert_eq!(parse("port 8080"), Err((1, "expected `=` after the key")));
+  }
+}
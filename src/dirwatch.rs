@@ -1,43 +1,47 @@
-use crate::channels::{Receiver, Sender};
+use crate::channels::{Full, Receiver, Select, Selected, Sender};
 use crate::error::Error;
+use crate::glob::Glob;
+use crate::log;
 use crate::server::Event;
 use libc::{inotify_add_watch, inotify_event, inotify_init1, read, EAGAIN, EWOULDBLOCK, IN_CLOSE_WRITE};
 use std::collections::HashMap;
 use std::ffi::{CStr, CString};
 use std::fs;
-use std::path::Path;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use std::{io, thread};
 
-pub use libc::{IN_CREATE, IN_DELETE, IN_DELETE_SELF, IN_IGNORED, IN_MODIFY};
+pub use libc::{IN_CREATE, IN_DELETE, IN_DELETE_SELF, IN_IGNORED, IN_MODIFY, IN_MOVED_FROM, IN_MOVED_TO};
 
 const EVENT_SIZE: usize = std::mem::size_of::<inotify_event>();
 const BUF_LEN: usize = 1024 * (EVENT_SIZE + 16);
 
-pub fn watch_dir(path: &Path, mask: u32, tx: Sender<Event>) -> Result<(), Error> {
+/// Sends a file change event for everything under `path` matching `mask`, except for the paths
+/// matching one of the `ignore` globs and the `skip` directories (which aren't watched at all).
+pub fn watch_dir(path: &Path, mask: u32, ignore: &[Glob], skip: &[PathBuf], tx: Sender<Event>) -> Result<(), Error> {
   let fd = unsafe { inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
   if fd < 0 {
     return Err(Error::InotifyInit(io::Error::last_os_error()));
   }
 
-  let stop = Arc::new(AtomicBool::new(false));
-  let server_events = {
-    let rx = Receiver::from(&tx);
-    let stop = stop.clone();
-
-    thread::spawn(move || loop {
-      let event = rx.recv();
-      if matches!(event, Event::Quit) {
-        stop.store(true, Ordering::Release);
-        break;
-      }
-    })
-  };
-
   let mut wd_to_path = HashMap::new();
-  fn add_watch_recursive(fd: i32, path: &Path, wd_to_path: &mut HashMap<i32, PascalString>, mut mask: u32) -> Result<(), Error> {
+  fn add_watch_recursive(
+    fd: i32,
+    path: &Path,
+    wd_to_path: &mut HashMap<i32, PascalString>,
+    mut mask: u32,
+    ignore: &[Glob],
+    skip: &[PathBuf],
+  ) -> Result<(), Error> {
+    let path_str = path.to_str().ok_or(Error::NonUtf8)?;
+    if is_ignored(ignore, path_str) || is_ignored(ignore, &format!("{path_str}/")) {
+      return Ok(());
+    }
+    // `skip` is canonical, `path` is as given.
+    if !skip.is_empty() && path.canonicalize().is_ok_and(|path| skip.contains(&path)) {
+      return Ok(());
+    }
+
     mask |= IN_CREATE;
     let path_c = CString::new(path.to_str().unwrap().as_bytes())?;
     let wd = unsafe { inotify_add_watch(fd, path_c.as_ptr(), mask) };
@@ -51,29 +55,33 @@ pub fn watch_dir(path: &Path, mask: u32, tx: Sender<Event>) -> Result<(), Error>
       let entry = entry?;
       let path = entry.path();
       if path.is_dir() {
-        add_watch_recursive(fd, &path, wd_to_path, mask)?;
+        add_watch_recursive(fd, &path, wd_to_path, mask, ignore, skip)?;
       }
     }
 
     Ok(())
   }
 
-  add_watch_recursive(fd, path, &mut wd_to_path, mask)?;
+  add_watch_recursive(fd, path, &mut wd_to_path, mask, ignore, skip)?;
 
   let mut buffer = [0; BUF_LEN];
+  let rx = Receiver::from(&tx);
+  let mut select = Select::new();
+  select.recv(&rx).fd(fd);
 
   loop {
+    match select.select() {
+      Selected::Message(Event::Quit) => break,
+      Selected::Message(_) => continue,
+      Selected::Fd => (),
+    }
+
     let length = unsafe { read(fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
     if length < 0 {
       let err = io::Error::last_os_error();
       let err_os = err.raw_os_error();
 
       if err_os == Some(EAGAIN) || err_os == Some(EWOULDBLOCK) {
-        if stop.load(Ordering::Acquire) {
-          break;
-        }
-
-        thread::sleep(Duration::from_millis(10));
         continue;
       }
 
@@ -84,31 +92,107 @@ pub fn watch_dir(path: &Path, mask: u32, tx: Sender<Event>) -> Result<(), Error>
     while i < length as usize {
       let event = unsafe { &*(buffer.as_ptr().add(i) as *const inotify_event) };
 
-      let event_name = extract_event_name(event, &buffer)?;
+      let event_name = extract_event_name(event, &buffer[i..])?;
       if event.mask & IN_CREATE != 0 {
         let new_path = path.join(event_name);
 
         if new_path.is_dir() {
-          add_watch_recursive(fd, &new_path, &mut wd_to_path, mask)?;
+          add_watch_recursive(fd, &new_path, &mut wd_to_path, mask, ignore, skip)?;
         }
       }
 
       if event.mask & mask != 0 {
         let mut dir = *wd_to_path.get(&event.wd).expect("event wd not mapped");
         dir.extend(b"/").extend(event_name.as_bytes());
+        if is_ignored(ignore, dir.as_str()) {
+          i += EVENT_SIZE + event.len as usize;
+          continue;
+        }
+
         log_event(event, dir.as_str());
-        tx.send(Event::FileChange(dir));
+        let change = Change {
+          path: dir,
+          kind: ChangeKind::from_mask(event.mask),
+        };
+        if !send_event(&tx, &rx, Event::FileChange(change)) {
+          return Ok(());
+        }
       }
 
       i += EVENT_SIZE + event.len as usize;
     }
   }
 
-  server_events.join().unwrap();
-
   Ok(())
 }
 
+fn is_ignored(ignore: &[Glob], path: &str) -> bool {
+  ignore.iter().any(|glob| glob.matches(path))
+}
+
+/// Sends `event` without letting our own receiver hold back the bus when it's lossless.
+/// Returns false if a quit event arrived while waiting.
+fn send_event(tx: &Sender<Event>, rx: &Receiver<Event>, mut event: Event) -> bool {
+  loop {
+    match tx.try_send(event) {
+      Ok(()) => return true,
+      Err(Full(e)) => event = e,
+    }
+
+    while let Some(e) = rx.recv_some() {
+      if matches!(e, Event::Quit) {
+        return false;
+      }
+    }
+
+    thread::sleep(Duration::from_millis(1));
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+  Create,
+  Modify,
+  Delete,
+}
+
+impl ChangeKind {
+  fn from_mask(mask: u32) -> Self {
+    if mask & (IN_CREATE | IN_MOVED_TO) != 0 {
+      ChangeKind::Create
+    }
+    else if mask & (IN_DELETE | IN_MOVED_FROM) != 0 {
+      ChangeKind::Delete
+    }
+    else {
+      ChangeKind::Modify
+    }
+  }
+
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      ChangeKind::Create => "create",
+      ChangeKind::Modify => "modify",
+      ChangeKind::Delete => "delete",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "create" => Some(ChangeKind::Create),
+      "modify" => Some(ChangeKind::Modify),
+      "delete" => Some(ChangeKind::Delete),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Change {
+  pub path: PascalString,
+  pub kind: ChangeKind,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct PascalString {
   len: u8,
@@ -116,7 +200,7 @@ pub struct PascalString {
 }
 
 impl PascalString {
-  fn new(data: &[u8]) -> Self {
+  pub fn new(data: &[u8]) -> Self {
     let mut buf = [0; 128];
     buf[..data.len()].copy_from_slice(data);
     Self { len: data.len() as u8, buf }
@@ -133,8 +217,8 @@ impl PascalString {
     &self.buf[..self.len as usize]
   }
 
-  fn as_str(&self) -> &str {
-    unsafe { std::str::from_utf8_unchecked(&self.buf) }
+  pub fn as_str(&self) -> &str {
+    unsafe { std::str::from_utf8_unchecked(self.as_bytes()) }
   }
 }
 
@@ -152,6 +236,12 @@ fn log_event(event: &inotify_event, name: &str) {
   if mask & IN_DELETE != 0 {
     mask_str.push_str("IN_DELETE ");
   }
+  if mask & IN_MOVED_FROM != 0 {
+    mask_str.push_str("IN_MOVED_FROM ");
+  }
+  if mask & IN_MOVED_TO != 0 {
+    mask_str.push_str("IN_MOVED_TO ");
+  }
   if mask & IN_CLOSE_WRITE != 0 {
     mask_str.push_str("IN_CLOSE_WRITE ");
   }
@@ -162,9 +252,10 @@ fn log_event(event: &inotify_event, name: &str) {
     mask_str.push_str("IN_IGNORED ");
   }
 
-  println!("\x1b[38;5;123mFile Change:\x1b[0m WD: {}, Mask: {}, Name: {}", wd, mask_str.trim(), name);
+  log::info("File change", format!("WD: {}, Mask: {}, Name: {}", wd, mask_str.trim(), name));
 }
 
+/// The name following `event`, which starts `buffer`.
 fn extract_event_name<'a>(event: &inotify_event, buffer: &'a [u8]) -> Result<&'a str, Error> {
   let name_len = event.len as usize;
   if name_len > 0 {
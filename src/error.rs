@@ -2,6 +2,7 @@ use std::{
   ffi::NulError,
   fmt::{Debug, Display},
   io,
+  path::PathBuf,
   str::Utf8Error,
 };
 
@@ -13,6 +14,18 @@ pub enum Error {
   Utf8(Utf8Error),
   NonUtf8,
   Nul(NulError),
+  ReplayParse(usize),
+  UnterminatedQuote,
+  ConfigRead(PathBuf, io::Error),
+  ConfigParse(PathBuf, usize, &'static str),
+  ConfigKey(String),
+  ConfigValue(String),
+  UnexpectedArg(String),
+  UnknownOption(String, Option<&'static str>),
+  RepeatedOption(&'static str),
+  MissingValue(&'static str),
+  InvalidValue(&'static str, String),
+  ConflictingOptions(&'static str, &'static str),
 }
 
 impl From<io::Error> for Error {
@@ -43,6 +56,19 @@ impl Display for Error {
       Self::Utf8(err) => write!(f, "{err}"),
       Self::NonUtf8 => write!(f, "Only utf8 file names are supported"),
       Self::Nul(err) => write!(f, "{err}"),
+      Self::ReplayParse(line) => write!(f, "Invalid event on line {line} of the replay file"),
+      Self::UnterminatedQuote => write!(f, "Unterminated quote in command"),
+      Self::ConfigRead(path, err) => write!(f, "Failed to read {}: {err}", path.display()),
+      Self::ConfigParse(path, line, msg) => write!(f, "Invalid config on line {line} of {}: {msg}", path.display()),
+      Self::ConfigKey(key) => write!(f, "Unknown config key `{key}`"),
+      Self::ConfigValue(key) => write!(f, "Wrong type for config key `{key}`"),
+      Self::UnexpectedArg(arg) => write!(f, "Unexpected argument `{arg}`, see `dirwatch --help`"),
+      Self::UnknownOption(arg, Some(close)) => write!(f, "Unknown option `{arg}`, did you mean `-{close}`?"),
+      Self::UnknownOption(arg, None) => write!(f, "Unknown option `{arg}`, see `dirwatch --help`"),
+      Self::RepeatedOption(name) => write!(f, "`-{name}` can only be given once"),
+      Self::MissingValue(name) => write!(f, "Missing value for `-{name}`"),
+      Self::InvalidValue(name, value) => write!(f, "Invalid value `{value}` for `-{name}`"),
+      Self::ConflictingOptions(a, b) => write!(f, "`-{a}` can't be used with `-{b}`"),
     }
   }
 }
@@ -0,0 +1,63 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Files git keeps while an operation is in progress, with the operation's name. Bisecting isn't
+/// included, each of its steps is a checkout that should be built.
+const OPERATIONS: &[(&str, &str)] = &[
+  ("index.lock", "checkout"),
+  ("rebase-merge", "rebase"),
+  ("rebase-apply", "rebase"),
+  ("REBASE_HEAD", "rebase"),
+  ("MERGE_HEAD", "merge"),
+  ("CHERRY_PICK_HEAD", "cherry-pick"),
+  ("REVERT_HEAD", "revert"),
+];
+
+/// The git directory of the repository containing `dir`, if any.
+pub fn git_dir(dir: &Path) -> Option<PathBuf> {
+  let dir = dir.canonicalize().ok()?;
+  for dir in dir.ancestors() {
+    let dot_git = dir.join(".git");
+    if dot_git.is_dir() {
+      return Some(dot_git);
+    }
+    // Worktrees and submodules have a file pointing to the actual git directory instead.
+    if let Ok(contents) = fs::read_to_string(&dot_git) {
+      let git_dir = contents.strip_prefix("gitdir:")?.trim();
+      return Some(dir.join(git_dir));
+    }
+  }
+  None
+}
+
+/// The name of the operation in progress in `git_dir`.
+pub fn operation(git_dir: &Path) -> Option<&'static str> {
+  OPERATIONS
+    .iter()
+    .find(|(file, _)| git_dir.join(file).symlink_metadata().is_ok())
+    .map(|(_, name)| *name)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn finds_operations_in_progress() {
+    let repo = std::env::temp_dir().join(format!("dirwatch-git-{}", std::process::id()));
+    let src = repo.join("src");
+    fs::create_dir_all(repo.join(".git")).unwrap();
+    fs::create_dir_all(&src).unwrap();
+
+    let git_dir = git_dir(&src).unwrap();
+    assert_eq!(git_dir, repo.canonicalize().unwrap().join(".git"));
+    assert_eq!(operation(&git_dir), None);
+    fs::write(git_dir.join("MERGE_HEAD"), "").unwrap();
+    assert_eq!(operation(&git_dir), Some("merge"));
+    fs::remove_file(git_dir.join("MERGE_HEAD")).unwrap();
+    fs::create_dir(git_dir.join("rebase-merge")).unwrap();
+    assert_eq!(operation(&git_dir), Some("rebase"));
+
+    fs::remove_dir_all(&repo).unwrap();
+  }
+}
@@ -0,0 +1,102 @@
+/// A shell style pattern: `*` and `?` don't cross `/`, `**` does and `{a,b}` matches either.
+///
+/// Patterns are matched against the end of a path at a `/` boundary, so `*.scss` matches in any
+/// directory while `src/*.ts` only matches files directly inside a `src` directory.
+pub struct Glob {
+  patterns: Vec<String>,
+}
+
+impl Glob {
+  pub fn new(glob: &str) -> Self {
+    Self { patterns: expand_braces(glob) }
+  }
+
+  pub fn matches(&self, path: &str) -> bool {
+    let mut starts = std::iter::once(0).chain(path.match_indices('/').map(|(i, _)| i + 1));
+    starts.any(|i| self.patterns.iter().any(|p| match_here(p.as_bytes(), &path.as_bytes()[i..])))
+  }
+}
+
+fn match_here(pattern: &[u8], s: &[u8]) -> bool {
+  match pattern {
+    [] => s.is_empty(),
+    [b'*', b'*', b'/', rest @ ..] => match_here(rest, s) || s.iter().enumerate().any(|(i, &c)| c == b'/' && match_here(rest, &s[i + 1..])),
+    [b'*', b'*', rest @ ..] => (0..=s.len()).any(|i| match_here(rest, &s[i..])),
+    [b'*', rest @ ..] => {
+      for i in 0..=s.len() {
+        if match_here(rest, &s[i..]) {
+          return true;
+        }
+        if i == s.len() || s[i] == b'/' {
+          break;
+        }
+      }
+      false
+    }
+    [b'?', rest @ ..] => matches!(s, [c, s @ ..] if *c != b'/' && match_here(rest, s)),
+    [p, rest @ ..] => matches!(s, [c, s @ ..] if c == p && match_here(rest, s)),
+  }
+}
+
+/// Expands the first `{a,b}` group (and recursively the rest), unbalanced braces are literal.
+fn expand_braces(glob: &str) -> Vec<String> {
+  let Some(open) = glob.find('{')
+  else {
+    return vec![glob.to_string()];
+  };
+
+  let mut depth = 0;
+  let mut alternatives = Vec::new();
+  let mut start = open + 1;
+  for (i, c) in glob[open..].char_indices().map(|(i, c)| (i + open, c)) {
+    match c {
+      '{' => depth += 1,
+      '}' => {
+        depth -= 1;
+        if depth == 0 {
+          alternatives.push(&glob[start..i]);
+          let (prefix, suffix) = (&glob[..open], &glob[i + 1..]);
+          return alternatives
+            .iter()
+            .flat_map(|alt| expand_braces(&format!("{prefix}{alt}{suffix}")))
+            .collect();
+        }
+      }
+      ',' if depth == 1 => {
+        alternatives.push(&glob[start..i]);
+        start = i + 1;
+      }
+      _ => (),
+    }
+  }
+
+  vec![glob.to_string()]
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn globs_match_path_suffixes() {
+    let cases = [
+      ("*.scss", "./styles/main.scss", true),
+      ("*.scss", "./styles/main.css", false),
+      ("src/*.ts", "/tmp/app/src/main.ts", true),
+      ("src/*.ts", "/tmp/app/src/lib/util.ts", false),
+      ("src/**/*.ts", "/tmp/app/src/lib/util.ts", true),
+      ("src/**/*.ts", "/tmp/app/src/main.ts", true),
+      ("docs/**", "./docs/a/b.md", true),
+      ("*.{ts,tsx}", "./ui/app.tsx", true),
+      ("*.{ts,tsx}", "./ui/app.js", false),
+      ("?.md", "./a.md", true),
+      ("?.md", "./ab.md", false),
+      ("main.ts", "./src/domain.ts", false),
+      ("{a", "./{a", true),
+    ];
+
+    for (glob, path, expected) in cases {
+      assert_eq!(Glob::new(glob).matches(path), expected, "{glob} on {path}");
+    }
+  }
+}
@@ -2,33 +2,25 @@ use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::net::{SocketAddr, TcpStream};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
 use std::ops::{Deref, DerefMut, Range};
 use std::path::Path;
 use std::str;
 
 use crate::error::Error;
 
+/// 10 MB, the largest body read and part of a file sent at once.
+pub const MAX_CONTENT_LEN: usize = 10 * 1000 * 1000;
+
 #[derive(Debug)]
 pub struct HttpRequest {
   pub method: HttpMethod,
   pub path: Box<str>,
-  pub peer_addr: SocketAddr,
+  /// The path as requested, with the query string.
+  pub target: Box<str>,
   pub headers: HttpHeaders,
-}
-
-impl Display for HttpRequest {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    write!(
-      f,
-      "[\x1b[93m  {}\x1b[0m] \x1b[33m󰋻 {:?} {}\x1b[0m | \x1b[36m󰮤 {}\x1b[0m",
-      self.peer_addr,
-      self.method,
-      self.path,
-      self.headers.get("user-agent").unwrap_or(&"No user agent".into()),
-    )
-  }
+  pub body: Vec<u8>,
 }
 
 impl HttpRequest {
@@ -48,34 +40,39 @@ impl HttpRequest {
     })
   }
 
-  pub fn from_ip(peer_addr: SocketAddr) -> Self {
+  pub fn new() -> Self {
     Self {
       path: "".into(),
-      peer_addr,
+      target: "".into(),
       method: HttpMethod::Unknown,
       headers: HttpHeaders(HashMap::new()),
+      body: Vec::new(),
     }
   }
 
   /// Returns whether the request was changed or not.
   pub fn read_from_buffer(&mut self, buffer: &[u8]) -> Result<bool, Error> {
-    let request = str::from_utf8(buffer)?;
+    // Whatever was read past the headers is the start of the body.
+    let head_len = buffer.windows(4).position(|w| w == b"\r\n\r\n").map_or(buffer.len(), |i| i + 4);
+    let request = str::from_utf8(&buffer[..head_len])?;
 
     let mut lines = request.lines();
     let Some(mut method) = lines.next().map(|ln| ln.split_whitespace())
     else {
       return Ok(false);
     };
-    let (method, path) = (method.next().unwrap_or_default(), method.next().unwrap_or("???"));
+    let (method, target) = (method.next().unwrap_or_default(), method.next().unwrap_or("???"));
 
-    let path = match path.bytes().position(|b| b == b'?' || b == b'#') {
-      Some(pos) => &path[..pos],
-      None => path,
+    let path = match target.bytes().position(|b| b == b'?' || b == b'#') {
+      Some(pos) => &target[..pos],
+      None => target,
     };
 
     self.method = method.into();
+    self.target = target.into();
     self.path = path.into();
     self.headers.clear();
+    self.body = buffer[head_len..].to_vec();
 
     for line in lines {
       let Some((k, v)) = line.split_once(':')
@@ -88,6 +85,53 @@ impl HttpRequest {
 
     Ok(true)
   }
+
+  /// The length of the body announced by `content-length`.
+  pub fn content_len(&self) -> usize {
+    self.headers.get("content-length").and_then(|l| l.trim().parse().ok()).unwrap_or(0)
+  }
+
+  /// Whether the whole body is there.
+  pub fn has_body(&self) -> bool {
+    self.body.len() >= self.content_len()
+  }
+
+  /// Reads the rest of the body announced by `content-length`, unless it's over `MAX_CONTENT_LEN`.
+  pub fn read_body(&mut self, stream: &mut TcpStream) -> Result<(), Error> {
+    let len = self.content_len();
+    if len > MAX_CONTENT_LEN {
+      return Ok(());
+    }
+    if self.body.len() < len {
+      let start = self.body.len();
+      self.body.resize(len, 0);
+      stream.read_exact(&mut self.body[start..])?;
+    }
+    self.body.truncate(len);
+    Ok(())
+  }
+}
+
+/// Forwards `req` to the HTTP server at `backend` (`host:port`, optionally prefixed with `http://`)
+/// and copies its response back to `stream`.
+pub fn proxy(req: &HttpRequest, backend: &str, stream: &mut TcpStream) -> Result<(), Error> {
+  let backend = backend.trim_start_matches("http://").trim_end_matches('/');
+  let mut upstream = TcpStream::connect(backend)?;
+
+  write!(
+    upstream,
+    "{} {} HTTP/1.1\r\nhost: {backend}\r\nconnection: close\r\n",
+    req.method.as_str(),
+    req.target
+  )?;
+  for (k, v) in req.headers.iter().filter(|(k, _)| *k != "host" && *k != "connection") {
+    write!(upstream, "{k}: {v}\r\n")?;
+  }
+  upstream.write_all(b"\r\n")?;
+  upstream.write_all(&req.body)?;
+
+  io::copy(&mut upstream, stream)?;
+  Ok(())
 }
 
 pub fn read_request_headers(stream: &mut TcpStream) -> Result<Vec<u8>, Error> {
@@ -160,9 +204,6 @@ impl HttpResponse {
       return Ok(());
     };
 
-    /// 10 MB
-    const MAX_CONTENT_LEN: usize = 10 * 1000 * 1000;
-
     let meta = file.metadata()?;
     let file_size = meta.len() as usize;
     let content_type = get_mime_type(path.as_ref());
@@ -210,6 +251,8 @@ impl HttpResponse {
       200 => "Ok",
       206 => "Partial Content",
       404 => "Not Found",
+      413 => "Content Too Large",
+      502 => "Bad Gateway",
       s => todo!("Http status text for {s}"),
     }
   }
@@ -268,6 +311,23 @@ pub enum HttpMethod {
   Unknown,
 }
 
+impl HttpMethod {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Self::Get => "GET",
+      Self::Post => "POST",
+      Self::Put => "PUT",
+      Self::Delete => "DELETE",
+      Self::Patch => "PATCH",
+      Self::Options => "OPTIONS",
+      Self::Head => "HEAD",
+      Self::Trace => "TRACE",
+      Self::Connect => "CONNECT",
+      Self::Unknown => "UNKNOWN",
+    }
+  }
+}
+
 impl From<&str> for HttpMethod {
   fn from(value: &str) -> Self {
     match value.to_uppercase().as_str() {
@@ -0,0 +1,276 @@
+use std::env;
+use std::fmt::Display;
+use std::io::{self, IsTerminal, Write};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+  Error,
+  Warn,
+  Info,
+  Debug,
+}
+
+impl Level {
+  fn as_str(&self) -> &'static str {
+    match self {
+      Level::Error => "error",
+      Level::Warn => "warn",
+      Level::Info => "info",
+      Level::Debug => "debug",
+    }
+  }
+
+  fn color(&self) -> &'static str {
+    match self {
+      Level::Error => "38;5;203",
+      Level::Warn => "38;5;210",
+      Level::Info => "38;5;123",
+      Level::Debug => "38;5;245",
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+  Text,
+  /// One JSON object per line, command output included.
+  Json,
+}
+
+impl FromStr for Format {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "text" => Ok(Format::Text),
+      "json" => Ok(Format::Json),
+      _ => Err(()),
+    }
+  }
+}
+
+struct Logger {
+  level: Level,
+  format: Format,
+  /// Colors and glyphs, only on a terminal without `NO_COLOR`.
+  styled: bool,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Sets the level and format, lines logged before use `info` and text.
+pub fn init(level: Level, format: Format) {
+  let no_color = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+  let styled = format == Format::Text && io::stdout().is_terminal() && !no_color;
+  let _ = LOGGER.set(Logger { level, format, styled });
+}
+
+/// Whether lines are colored for a terminal, escape codes shouldn't be written otherwise.
+pub fn styled() -> bool {
+  logger().styled
+}
+
+fn logger() -> &'static Logger {
+  LOGGER.get_or_init(|| Logger {
+    level: Level::Info,
+    format: Format::Text,
+    styled: false,
+  })
+}
+
+/// Lines about a connection, prefixed with the client's address.
+pub fn peer(addr: SocketAddr) -> Log {
+  Log { peer: Some(addr) }
+}
+
+pub fn error(title: &str, detail: impl Display) {
+  Log::default().write(Level::Error, title, detail);
+}
+
+pub fn warn(title: &str, detail: impl Display) {
+  Log::default().write(Level::Warn, title, detail);
+}
+
+pub fn info(title: &str, detail: impl Display) {
+  Log::default().write(Level::Info, title, detail);
+}
+
+pub fn debug(title: &str, detail: impl Display) {
+  Log::default().write(Level::Debug, title, detail);
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct Log {
+  peer: Option<SocketAddr>,
+}
+
+impl Log {
+  pub fn error(&self, title: &str, detail: impl Display) {
+    self.write(Level::Error, title, detail);
+  }
+
+  pub fn warn(&self, title: &str, detail: impl Display) {
+    self.write(Level::Warn, title, detail);
+  }
+
+  pub fn info(&self, title: &str, detail: impl Display) {
+    self.write(Level::Info, title, detail);
+  }
+
+  pub fn debug(&self, title: &str, detail: impl Display) {
+    self.write(Level::Debug, title, detail);
+  }
+
+  /// Errors go to stderr and everything else to stdout.
+  fn write(&self, level: Level, title: &str, detail: impl Display) {
+    let logger = logger();
+    if level > logger.level {
+      return;
+    }
+
+    let line = self.line(logger, level, title, &detail.to_string());
+    if level == Level::Error {
+      eprintln!("{line}");
+    }
+    else {
+      println!("{line}");
+    }
+  }
+
+  /// `title: detail`, the detail being optional.
+  fn line(&self, logger: &Logger, level: Level, title: &str, detail: &str) -> String {
+    if logger.format == Format::Json {
+      let mut fields = vec![("level", level.as_str()), ("msg", title)];
+      let peer = self.peer.map(|peer| peer.to_string());
+      fields.extend(peer.as_deref().map(|peer| ("peer", peer)));
+      if !detail.is_empty() {
+        fields.push(("detail", detail));
+      }
+      return json_line(&fields);
+    }
+
+    let mut line = String::new();
+    match self.peer {
+      Some(peer) if logger.styled => line.push_str(&format!("[\x1b[93m\u{f2c2}  {peer}\x1b[0m] ")),
+      Some(peer) => line.push_str(&format!("[{peer}] ")),
+      None => (),
+    }
+    let colon = if detail.is_empty() { "" } else { ":" };
+    if logger.styled {
+      line.push_str(&format!("\x1b[{}m{title}{colon}\x1b[0m", level.color()));
+    }
+    else {
+      line.push_str(&format!("{title}{colon}"));
+    }
+    if !detail.is_empty() {
+      line.push(' ');
+      line.push_str(detail);
+    }
+    line
+  }
+}
+
+/// A JSON object with the time in seconds since the epoch followed by `fields`.
+fn json_line(fields: &[(&str, &str)]) -> String {
+  let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+  let mut line = format!(r#"{{"time":{time:.3}"#);
+  for (key, value) in fields {
+    line.push_str(&format!(",{}:{}", json_string(key), json_string(value)));
+  }
+  line.push('}');
+  line
+}
+
+fn json_string(s: &str) -> String {
+  let mut json = String::from("\"");
+  for c in s.chars() {
+    match c {
+      '"' => json.push_str("\\\""),
+      '\\' => json.push_str("\\\\"),
+      '\n' => json.push_str("\\n"),
+      '\r' => json.push_str("\\r"),
+      '\t' => json.push_str("\\t"),
+      c if c < ' ' => json.push_str(&format!("\\u{:04x}", c as u32)),
+      c => json.push(c),
+    }
+  }
+  json.push('"');
+  json
+}
+
+/// Where command output is relayed, with JSON logs each line becomes a log line of its own.
+pub fn output(stderr: bool) -> Box<dyn Write + Send> {
+  match (logger().format, stderr) {
+    (Format::Text, false) => Box::new(io::stdout()),
+    (Format::Text, true) => Box::new(io::stderr()),
+    (Format::Json, _) => Box::new(JsonLines {
+      stream: if stderr { "stderr" } else { "stdout" },
+      partial: Vec::new(),
+    }),
+  }
+}
+
+struct JsonLines {
+  stream: &'static str,
+  /// The end of the output that isn't a complete line yet.
+  partial: Vec<u8>,
+}
+
+impl JsonLines {
+  fn emit(&self, line: &[u8]) {
+    let line = String::from_utf8_lossy(line);
+    println!("{}", json_line(&[("level", "info"), ("msg", "Output"), ("stream", self.stream), ("detail", &line)]));
+  }
+}
+
+impl Write for JsonLines {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.partial.extend_from_slice(buf);
+    while let Some(end) = self.partial.iter().position(|&b| b == b'\n') {
+      let line = self.partial.drain(..=end).collect::<Vec<_>>();
+      self.emit(&line[..end]);
+    }
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    io::stdout().flush()
+  }
+}
+
+impl Drop for JsonLines {
+  fn drop(&mut self) {
+    if !self.partial.is_empty() {
+      self.emit(&self.partial);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn lines_follow_the_format() {
+    let text = Logger {
+      level: Level::Info,
+      format: Format::Text,
+      styled: false,
+    };
+    let peer = "127.0.0.1:5000".parse().unwrap();
+    assert_eq!(
+      Log::default().line(&text, Level::Warn, "Build failed", "exit status: 1"),
+      "Build failed: exit status: 1"
+    );
+    assert_eq!(super::peer(peer).line(&text, Level::Info, "SSE connected", ""), "[127.0.0.1:5000] SSE connected");
+
+    let json = Logger { format: Format::Json, ..text };
+    let line = super::peer(peer).line(&json, Level::Error, "Proxy failed", "\"x\"\n");
+    let fields = line.split_once(',').unwrap().1;
+    assert_eq!(fields, r#""level":"error","msg":"Proxy failed","peer":"127.0.0.1:5000","detail":"\"x\"\n"}"#);
+  }
+}
@@ -1,23 +1,41 @@
 mod channels;
 mod cli;
+mod config;
 mod dirwatch;
 mod error;
+mod git;
+mod glob;
 mod http;
+mod log;
+mod net;
+mod record;
 mod server;
 
-use cli::Cli;
+use cli::{Args, Cli};
 use error::Error;
+use std::{env, process};
 
 #[cfg(unix)]
 fn main() -> Result<(), Error> {
-  if cli::find_flag("-h") {
-    println!("{}", Cli::USAGE);
+  let args = Args::parse(env::args().skip(1))?;
+  if args.has("help") {
+    print!("{}", cli::help());
     return Ok(());
   }
+  if args.has("version") {
+    println!("dirwatch {}", env!("CARGO_PKG_VERSION"));
+    return Ok(());
+  }
+
+  let cli = Cli::parse(&args)?;
+  log::init(cli.log_level, cli.log_format);
+  if cli.once {
+    let status = server::run_once(&cli)?;
+    process::exit(status.code().unwrap_or(1));
+  }
 
-  let args = Cli::parse()?;
-  server::run_server(&args)?;
-  println!("Main exit");
+  server::run_server(&cli)?;
+  log::debug("Main exit", "");
 
   Ok(())
 }
@@ -0,0 +1,132 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
+use std::os::fd::FromRawFd;
+
+/// Parses `-host`, an IP address with IPv6 ones optionally in brackets, or `localhost`.
+pub fn parse_host(host: &str) -> Option<IpAddr> {
+  if host == "localhost" {
+    return Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+  }
+  let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+  host.parse().ok()
+}
+
+/// Binds `addr`, `::` accepts IPv4 connections too whatever the system's default is.
+pub fn listen(addr: SocketAddr) -> io::Result<TcpListener> {
+  if addr.ip() != IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+    return TcpListener::bind(addr);
+  }
+
+  unsafe {
+    let fd = libc::socket(libc::AF_INET6, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0);
+    if fd < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    // Closes the socket if anything below fails.
+    let listener = TcpListener::from_raw_fd(fd);
+
+    for (level, name, value) in [
+      (libc::SOL_SOCKET, libc::SO_REUSEADDR, 1),
+      (libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, 0),
+    ] {
+      let value: libc::c_int = value;
+      let len = std::mem::size_of_val(&value) as libc::socklen_t;
+      if libc::setsockopt(fd, level, name, &value as *const _ as *const libc::c_void, len) < 0 {
+        return Err(io::Error::last_os_error());
+      }
+    }
+
+    // The zeroed address is `::`.
+    let mut sin6: libc::sockaddr_in6 = std::mem::zeroed();
+    sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+    sin6.sin6_port = addr.port().to_be();
+    let len = std::mem::size_of_val(&sin6) as libc::socklen_t;
+    if libc::bind(fd, &sin6 as *const _ as *const libc::sockaddr, len) < 0 || libc::listen(fd, 128) < 0 {
+      return Err(io::Error::last_os_error());
+    }
+
+    Ok(listener)
+  }
+}
+
+/// The URLs the server listening on `addr` can be reached at, `interfaces` being the addresses of
+/// this machine when it listens on all of them.
+pub fn urls(addr: SocketAddr, interfaces: &[IpAddr]) -> Vec<String> {
+  let port = addr.port();
+  let ip = addr.ip();
+  if !ip.is_unspecified() {
+    let mut urls = Vec::new();
+    if ip.is_loopback() {
+      urls.push(format!("http://localhost:{port}"));
+    }
+    urls.push(format!("http://{addr}"));
+    return urls;
+  }
+
+  let mut urls = vec![format!("http://localhost:{port}")];
+  for &ip in interfaces {
+    // Link-local IPv6 addresses need a zone, which browsers don't take in URLs.
+    let reachable = match ip {
+      IpAddr::V4(ip) => !ip.is_loopback(),
+      IpAddr::V6(v6) => addr.is_ipv6() && !v6.is_loopback() && v6.segments()[0] & 0xffc0 != 0xfe80,
+    };
+    if reachable {
+      urls.push(format!("http://{}", SocketAddr::new(ip, port)));
+    }
+  }
+  urls
+}
+
+/// The addresses of the network interfaces that are up.
+pub fn interface_addrs() -> Vec<IpAddr> {
+  let mut addrs = Vec::new();
+  let mut ifaddrs = std::ptr::null_mut();
+  if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+    return addrs;
+  }
+
+  let mut next = ifaddrs;
+  while let Some(ifaddr) = unsafe { next.as_ref() } {
+    next = ifaddr.ifa_next;
+    if ifaddr.ifa_addr.is_null() || ifaddr.ifa_flags & libc::IFF_UP as u32 == 0 {
+      continue;
+    }
+
+    match unsafe { (*ifaddr.ifa_addr).sa_family } as i32 {
+      libc::AF_INET => {
+        let sin = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in) };
+        addrs.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr))));
+      }
+      libc::AF_INET6 => {
+        let sin6 = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in6) };
+        addrs.push(IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr)));
+      }
+      _ => (),
+    }
+  }
+
+  unsafe { libc::freeifaddrs(ifaddrs) };
+  addrs
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn lists_reachable_urls() {
+    assert_eq!(parse_host("[::]"), Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED)));
+    assert_eq!(parse_host("localhost"), Some(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+    assert_eq!(parse_host("example.com"), None);
+
+    let interfaces = ["127.0.0.1", "192.168.1.20", "::1", "fe80::1", "2001:db8::20"].map(|ip| ip.parse().unwrap());
+    let urls = |addr: &str| urls(addr.parse().unwrap(), &interfaces);
+    assert_eq!(urls("127.0.0.1:8080"), ["http://localhost:8080", "http://127.0.0.1:8080"]);
+    assert_eq!(urls("192.168.1.20:8080"), ["http://192.168.1.20:8080"]);
+    assert_eq!(urls("0.0.0.0:8080"), ["http://localhost:8080", "http://192.168.1.20:8080"]);
+    assert_eq!(
+      urls("[::]:8080"),
+      ["http://localhost:8080", "http://192.168.1.20:8080", "http://[2001:db8::20]:8080"]
+    );
+  }
+}
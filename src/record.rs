@@ -0,0 +1,130 @@
+use crate::channels::{Receiver, Sender};
+use crate::dirwatch::{Change, ChangeKind, PascalString};
+use crate::error::Error;
+use crate::server::Event;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Writes every event `rx` sees to `path`, one `<millis since start> <event>` line each, until a
+/// quit event arrives.
+pub fn record_events(path: &Path, rx: Receiver<Event>) -> Result<(), Error> {
+  let mut file = BufWriter::new(File::create(path)?);
+  let start = Instant::now();
+
+  loop {
+    let event = rx.recv();
+    writeln!(file, "{} {}", start.elapsed().as_millis(), encode_event(&event))?;
+    file.flush()?;
+
+    if matches!(event, Event::Quit) {
+      break;
+    }
+  }
+
+  Ok(())
+}
+
+/// Sends the file changes recorded in `path` with their original timing.
+///
+/// Events the server derives on its own (like finished commands) are skipped so they aren't
+/// duplicated, returns whether the recording ended with a quit event.
+pub fn replay_events(path: &Path, tx: &Sender<Event>) -> Result<bool, Error> {
+  let file = BufReader::new(File::open(path)?);
+  let start = Instant::now();
+
+  for (i, line) in file.lines().enumerate() {
+    let line = line?;
+    if line.trim().is_empty() {
+      continue;
+    }
+
+    let (millis, event) = line
+      .split_once(' ')
+      .and_then(|(millis, event)| Some((millis.parse::<u64>().ok()?, decode_event(event)?)))
+      .ok_or(Error::ReplayParse(i + 1))?;
+
+    if let Some(wait) = Duration::from_millis(millis).checked_sub(start.elapsed()) {
+      thread::sleep(wait);
+    }
+
+    match event {
+      Event::FileChange(_) => tx.send(event),
+      Event::Quit => return Ok(true),
+      _ => (),
+    }
+  }
+
+  Ok(false)
+}
+
+fn encode_event(event: &Event) -> String {
+  match event {
+    Event::Start => "Start".into(),
+    Event::FileChange(change) => format!("FileChange {} {}", change.kind.as_str(), change.path.as_str()),
+    Event::CmdFinished(status) => format!("CmdFinished {}", status.into_raw()),
+    Event::DaemonExited(status) => format!("DaemonExited {}", status.into_raw()),
+    Event::Reload => "Reload".into(),
+    Event::HttpRequest => "HttpRequest".into(),
+    Event::StreamClosed => "StreamClosed".into(),
+    Event::Quit => "Quit".into(),
+  }
+}
+
+fn decode_event(s: &str) -> Option<Event> {
+  let (name, arg) = s.split_once(' ').unwrap_or((s, ""));
+
+  Some(match name {
+    "Start" => Event::Start,
+    "FileChange" => {
+      let (kind, path) = arg.split_once(' ')?;
+      if path.len() > 128 {
+        return None;
+      }
+
+      Event::FileChange(Change {
+        path: PascalString::new(path.as_bytes()),
+        kind: ChangeKind::from_name(kind)?,
+      })
+    }
+    "CmdFinished" => Event::CmdFinished(ExitStatus::from_raw(arg.parse().ok()?)),
+    "DaemonExited" => Event::DaemonExited(ExitStatus::from_raw(arg.parse().ok()?)),
+    "Reload" => Event::Reload,
+    "HttpRequest" => Event::HttpRequest,
+    "StreamClosed" => Event::StreamClosed,
+    "Quit" => Event::Quit,
+    _ => return None,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn events_round_trip() {
+    let events = [
+      Event::Start,
+      Event::FileChange(Change {
+        path: PascalString::new(b"src/some file.rs"),
+        kind: ChangeKind::Delete,
+      }),
+      Event::CmdFinished(ExitStatus::from_raw(0)),
+      Event::CmdFinished(ExitStatus::from_raw(1 << 8)),
+      Event::DaemonExited(ExitStatus::from_raw(9)),
+      Event::Reload,
+      Event::Quit,
+    ];
+
+    for event in events {
+      let decoded = decode_event(&encode_event(&event)).unwrap();
+      assert_eq!(encode_event(&decoded), encode_event(&event));
+    }
+
+    assert!(decode_event("Bogus").is_none());
+  }
+}
@@ -1,26 +1,36 @@
-use crate::channels::{Receiver, RingBuffer, Sender};
-use crate::cli::Cmd;
-use crate::dirwatch::PascalString;
+use crate::channels::{Full, Overflow, Receiver, RingBuffer, Select, Selected, Sender, Stats};
+use crate::cli::{self, Cmd, Daemon, Rule, KILL_GRACE};
+use crate::dirwatch::Change;
+use crate::glob::Glob;
 use crate::http::{read_request_headers, HttpMethod};
 use crate::{
   dirwatch,
   error::Error,
+  git, http,
   http::{HttpRequest, HttpResponse},
-  Cli,
+  log, net, record, Cli,
 };
 use readln::{read_key, Key};
-use std::io::Write;
+use std::io::{self, IsTerminal, Write};
 use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
-use std::path::Path;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
 #[derive(Debug, Clone, Copy)]
 pub enum Event {
   Start,
-  FileChange(PascalString),
-  CmdFinished,
-  HttpRequest(SocketAddr),
-  StreamClosed(SocketAddr),
+  FileChange(Change),
+  CmdFinished(ExitStatus),
+  DaemonExited(ExitStatus),
+  /// Reload the browsers without building.
+  Reload,
+  HttpRequest,
+  StreamClosed,
   Quit,
 }
 
@@ -38,54 +48,135 @@ fn inject_hr(req: &HttpRequest, res: &mut HttpResponse, path: &Path) -> Result<(
   Ok(())
 }
 
-fn handle_http(mut stream: TcpStream, dir_serve: &Path, rx: Receiver<Event>) -> Result<(), Error> {
+/// The backend `req` is proxied to, if any.
+fn proxy_backend<'a>(cli: &'a Cli, req: &HttpRequest) -> Option<&'a str> {
+  cli
+    .proxies
+    .iter()
+    .find(|(prefix, _)| req.path.starts_with(prefix.as_str()))
+    .map(|(_, backend)| backend.as_str())
+}
+
+/// Whether `req` is for a route that uses its body: the pause endpoints and proxied paths.
+fn takes_body(cli: &Cli, req: &HttpRequest) -> bool {
+  matches!(&*req.path, "/pause" | "/resume") || proxy_backend(cli, req).is_some()
+}
+
+fn handle_http(mut stream: TcpStream, cli: &Cli, controls: &Controls, build_log: &Mutex<String>, rx: Receiver<Event>) -> Result<(), Error> {
+  let dir_serve = &cli.dir_serve;
   let stream_ip = stream.peer_addr()?;
+  let log = log::peer(stream_ip);
 
   let is_sse = thread::scope(|s| -> Result<bool, Error> {
-    use std::sync::{Arc, Mutex};
-
-    let req = Arc::new(Mutex::new(HttpRequest::from_ip(stream_ip)));
+    let req = Arc::new(Mutex::new(HttpRequest::new()));
+    let (req_tx, req_rx) = RingBuffer::channel(8, Overflow::Block, Event::Start);
 
     {
-      let tx = Sender::from(&rx);
       let mut stream = stream.try_clone()?;
-      let ip = stream_ip;
       let req = req.clone();
 
       s.spawn(move || -> Result<(), Error> {
         loop {
           let headers = read_request_headers(&mut stream)?;
-          if !req.lock().unwrap().read_from_buffer(&headers)? {
-            tx.send(Event::StreamClosed(ip));
+          // Read without holding the lock, a client that stalls doesn't block the responses.
+          let mut next = HttpRequest::new();
+          if !next.read_from_buffer(&headers)? {
+            req_tx.send(Event::StreamClosed);
             return Ok(());
           };
+          if takes_body(cli, &next) {
+            next.read_body(&mut stream)?;
+          }
+          let has_body = next.has_body();
+          *req.lock().unwrap() = next;
+          req_tx.send(Event::HttpRequest);
 
-          tx.send(Event::HttpRequest(ip));
+          // What's left of a body that wasn't read would be taken for the next request.
+          if !has_body {
+            req_tx.send(Event::StreamClosed);
+            return Ok(());
+          }
         }
       });
     }
 
+    let mut select = Select::new();
+    select.recv(&req_rx).recv(&rx);
+
     let mut is_sse = false;
+    let mut dropped = 0;
     loop {
-      let event = rx.recv();
+      let event = match select.select() {
+        Selected::Message(event) => event,
+        Selected::Fd => continue,
+      };
+
+      if let Some(n) = newly_dropped(&rx, &mut dropped).filter(|_| is_sse) {
+        log.warn("SSE fell behind", format!("{n} events dropped"));
+      }
 
       match event {
-        Event::CmdFinished if is_sse => {
-          println!("[\x1b[93m  {}\x1b[0m] \x1b[32mFile Changed\x1b[0m", stream_ip);
-          send_sse_message(&mut stream)?;
+        Event::CmdFinished(status) if is_sse => {
+          if status.success() {
+            log.info("File changed", "");
+            send_sse_message(&mut stream, None, "File changed")?;
+          }
+          else {
+            log.info("Build failed", "");
+            let message = format!("{status}\n{}", build_log.lock().unwrap());
+            send_sse_message(&mut stream, Some("build-failed"), &message)?;
+          }
         }
-        Event::HttpRequest(ip) if ip == stream_ip => {
+        Event::Reload if is_sse => {
+          log.info("Reload requested", "");
+          send_sse_message(&mut stream, None, "Reload requested")?;
+        }
+        Event::DaemonExited(status) if is_sse => {
+          log.info("Daemon exited", "");
+          send_sse_message(&mut stream, Some("daemon-exited"), &status.to_string())?;
+        }
+        Event::HttpRequest => {
           let req = req.lock().unwrap();
-          println!("{}", req);
+          let user_agent = req.headers.get("user-agent").map_or("No user agent", |ua| ua);
+          log.info(&format!("{} {}", req.method.as_str(), req.path), user_agent);
+
+          if takes_body(cli, &req) && !req.has_body() {
+            HttpResponse::new()
+              .set_status(413)
+              .set_content(b"413 Content Too Large")
+              .write_to(&mut stream)?;
+            stream.shutdown(Shutdown::Write)?;
+            break;
+          }
+
+          if let Some(backend) = proxy_backend(cli, &req) {
+            // The backend closes its side once the response is sent, so does this connection.
+            if let Err(e) = http::proxy(&req, backend, &mut stream) {
+              log.error(&format!("Proxy to {backend} failed"), e);
+              HttpResponse::new()
+                .set_status(502)
+                .set_content(b"502 Bad Gateway")
+                .write_to(&mut stream)?;
+            }
+            stream.shutdown(Shutdown::Write)?;
+            break;
+          }
 
           let mut res = HttpResponse::new();
 
-          if matches!(req.method, HttpMethod::Get) {
+          if matches!(req.method, HttpMethod::Post) && matches!(&*req.path, "/pause" | "/resume") {
+            controls.set_paused(&*req.path == "/pause");
+            let paused = controls.paused.load(Ordering::Relaxed);
+            res
+              .set_header("content-type", "application/json")
+              .set_content(format!(r#"{{"paused":{paused}}}"#).as_bytes());
+          }
+          else if matches!(req.method, HttpMethod::Get) {
             let path = dir_serve.join(&req.path[1..]);
 
             if path.is_dir() {
               if let Err(e) = inject_hr(&req, &mut res, &path.join("index.html")) {
-                eprintln!("{e}");
+                log.error("Error serving the page", e);
                 res.set_404();
               }
             }
@@ -97,9 +188,14 @@ fn handle_http(mut stream: TcpStream, dir_serve: &Path, rx: Receiver<Event>) ->
                     .set_header("cache-control", "no-cache")
                     .set_header("connection", "keep-alive");
 
-                  println!("[\x1b[93m  {}\x1b[0m] \x1b[36mSSE Connected\x1b[0m", stream_ip);
+                  log.info("SSE connected", "");
                   is_sse = true;
                 }
+                "/status" => {
+                  res
+                    .set_header("content-type", "application/json")
+                    .set_content(stats_json(&rx.stats()).as_bytes());
+                }
                 _ => res.set_file(dir_serve.join(&req.path[1..]), &req)?,
               }
             }
@@ -108,9 +204,12 @@ fn handle_http(mut stream: TcpStream, dir_serve: &Path, rx: Receiver<Event>) ->
             res.set_404();
           }
 
+          for (k, v) in &cli.headers {
+            res.set_header(k.clone(), v.clone());
+          }
           res.write_to(&mut stream)?;
         }
-        Event::StreamClosed(ip) if ip == stream_ip => break,
+        Event::StreamClosed => break,
         Event::Quit => {
           stream.shutdown(Shutdown::Write)?;
           break;
@@ -123,97 +222,624 @@ fn handle_http(mut stream: TcpStream, dir_serve: &Path, rx: Receiver<Event>) ->
   })?;
 
   if is_sse {
-    println!("[\x1b[93m  {}\x1b[0m] \x1b[33mSSE Disconnected\x1b[0m", stream_ip);
+    log.info("SSE disconnected", "");
   }
   else {
-    println!("[\x1b[93m  {}\x1b[0m] \x1b[33mHttp Disconnected\x1b[0m", stream_ip);
+    log.debug("HTTP disconnected", "");
   }
 
   Ok(())
 }
 
-fn run_cmd(mut cmd: Cmd, tx: Receiver<Event>) -> Result<(), Error> {
-  loop {
-    let event = tx.recv();
+/// Returns how many events `rx` skipped since the last call, if any.
+fn newly_dropped(rx: &Receiver<Event>, dropped: &mut usize) -> Option<usize> {
+  let total = rx.dropped();
+  let new = total - std::mem::replace(dropped, total);
+  (new > 0).then_some(new)
+}
+
+fn stats_json(stats: &Stats) -> String {
+  let lags = stats.lags.iter().map(usize::to_string).collect::<Vec<_>>().join(",");
+  format!(
+    r#"{{"capacity":{},"sent":{},"version":{},"receivers":{},"lags":[{}],"dropped":{}}}"#,
+    stats.capacity,
+    stats.sent,
+    stats.version,
+    stats.lags.len(),
+    lags,
+    stats.dropped,
+  )
+}
+
+/// Adds `change` to `changes`, a path that changed again only keeps its latest kind.
+fn add_change(changes: &mut Vec<Change>, change: Change) {
+  match changes.iter_mut().find(|c| c.path.as_bytes() == change.path.as_bytes()) {
+    Some(c) => c.kind = change.kind,
+    None => changes.push(change),
+  }
+}
+
+/// Moves the changes already waiting in `rx` into `changes`, returns false if a quit event arrived.
+fn drain_changes(changes: &mut Vec<Change>, rx: &Receiver<Event>) -> bool {
+  while let Some(event) = rx.recv_some() {
     match event {
-      Event::FileChange(dir) => {
-        cmd.run_wait(dir.as_bytes())?;
-        tx.send(Event::CmdFinished);
+      Event::FileChange(change) => add_change(changes, change),
+      Event::Quit => return false,
+      _ => (),
+    }
+  }
+  true
+}
+
+/// Sends `event` through the command runner's own receiver `rx` without waiting on it when the bus
+/// is lossless, the changes read in the meantime go into `next`. Returns false if a quit event arrived.
+fn send_keeping_changes(rx: &Receiver<Event>, next: &mut Vec<Change>, mut event: Event) -> bool {
+  loop {
+    match rx.try_send(event) {
+      Ok(()) => return true,
+      Err(Full(e)) => event = e,
+    }
+
+    if !drain_changes(next, rx) {
+      return false;
+    }
+    thread::sleep(Duration::from_millis(1));
+  }
+}
+
+/// Fails with the first failing status, the outputs are concatenated in rule order.
+fn combine(results: Vec<(ExitStatus, Vec<u8>)>) -> (ExitStatus, Vec<u8>) {
+  let status = results.iter().map(|(status, _)| *status).find(|s| !s.success()).unwrap_or_default();
+  (status, results.into_iter().flat_map(|(_, output)| output).collect())
+}
+
+/// Runs every rule matching `changes` (or all of them) in parallel, in cancel mode new changes kill
+/// the running commands and restart them with the merged change set, otherwise (or while `controls`
+/// hold them) they're kept in `next`. Returns `None` after stopping the commands if a quit event
+/// arrived before they finished.
+fn build(
+  rules: &mut [Rule],
+  all: bool,
+  cancel: bool,
+  controls: &Controls,
+  changes: &mut Vec<Change>,
+  next: &mut Vec<Change>,
+  rx: &Receiver<Event>,
+) -> Result<Option<(ExitStatus, Vec<u8>)>, Error> {
+  'restart: loop {
+    let mut running = Vec::new();
+    for rule in rules.iter_mut() {
+      let matching = rule.matching(changes);
+      if all || !matching.is_empty() {
+        match rule.cmd.spawn(&matching) {
+          Ok(spawned) => running.extend(spawned),
+          // Like a command failing right away, the ones already started are stopped.
+          Err(e) => {
+            for running in running {
+              running.terminate(KILL_GRACE)?;
+            }
+            log::warn("Couldn't start the command", &e);
+            return Ok(Some((cli::not_started(), format!("Couldn't start the command: {e}\n").into_bytes())));
+          }
+        }
       }
-      Event::Quit => break,
+    }
+
+    let mut results = running.iter().map(|_| None).collect::<Vec<_>>();
+    loop {
+      for (running, result) in running.iter_mut().zip(&mut results) {
+        if result.is_none() {
+          *result = running.try_wait()?;
+        }
+      }
+      if results.iter().all(Option::is_some) {
+        return Ok(Some(combine(results.into_iter().flatten().collect())));
+      }
+
+      match rx.recv_some() {
+        Some(Event::FileChange(change)) if !cancel || controls.holding() => add_change(next, change),
+        Some(Event::FileChange(change)) => {
+          log::info("Changed during build", "restarting");
+          for running in running {
+            running.terminate(KILL_GRACE)?;
+          }
+
+          add_change(changes, change);
+          if !drain_changes(changes, rx) {
+            return Ok(None);
+          }
+
+          continue 'restart;
+        }
+        Some(Event::Quit) => {
+          for running in running {
+            running.terminate(KILL_GRACE)?;
+          }
+          return Ok(None);
+        }
+        Some(_) => (),
+        None => thread::sleep(Duration::from_millis(10)),
+      }
+    }
+  }
+}
+
+const WATCH_MASK: u32 = dirwatch::IN_MODIFY | dirwatch::IN_CREATE | dirwatch::IN_DELETE | dirwatch::IN_MOVED_FROM | dirwatch::IN_MOVED_TO;
+
+/// How often the daemon is checked for crashes while waiting for changes.
+const DAEMON_POLL: Duration = Duration::from_millis(100);
+
+/// Requests for the command runner, it picks them up between builds.
+#[derive(Default)]
+struct Controls {
+  /// Changes are held until resuming, then built together.
+  paused: AtomicBool,
+  /// Run every command once, whatever changed.
+  rebuild: AtomicBool,
+  /// Repositories of the watched directories, changes are also held while git works on them.
+  git_dirs: Vec<PathBuf>,
+}
+
+impl Controls {
+  fn new(dirs: &[PathBuf]) -> Self {
+    let mut git_dirs = dirs.iter().filter_map(|dir| git::git_dir(dir)).collect::<Vec<_>>();
+    git_dirs.sort();
+    git_dirs.dedup();
+    Controls { git_dirs, ..Default::default() }
+  }
+
+  /// Whether changes are held, paused or because of a git operation.
+  fn holding(&self) -> bool {
+    self.paused.load(Ordering::Relaxed) || self.git_operation().is_some()
+  }
+
+  /// The git operation in progress in one of the repositories, like a checkout or a rebase.
+  fn git_operation(&self) -> Option<&'static str> {
+    self.git_dirs.iter().find_map(|git_dir| git::operation(git_dir))
+  }
+
+  fn set_paused(&self, paused: bool) {
+    if self.paused.swap(paused, Ordering::Relaxed) != paused {
+      log_pause(paused);
+    }
+  }
+
+  fn toggle_pause(&self) {
+    log_pause(!self.paused.fetch_xor(true, Ordering::Relaxed));
+  }
+}
+
+fn log_pause(paused: bool) {
+  if paused {
+    log::info("Paused", "changes are held until resuming");
+  }
+  else {
+    log::info("Resumed", "");
+  }
+}
+
+fn run_cmd(
+  mut rules: Vec<Rule>,
+  mut after: Rule,
+  mut daemon: Daemon,
+  cancel: bool,
+  controls: &Controls,
+  build_log: Arc<Mutex<String>>,
+  tx: Receiver<Event>,
+) -> Result<(), Error> {
+  if let Err(e) = daemon.start() {
+    log::warn("Couldn't start the daemon", e);
+  }
+
+  let mut dropped = 0;
+  // Changes made while the previous build ran or while paused, they start the next build.
+  let mut next = Vec::new();
+  // The git operation the changes are held for.
+  let mut git_held = None;
+  loop {
+    let rebuild = controls.rebuild.swap(false, Ordering::Relaxed);
+    let git = if next.is_empty() { None } else { controls.git_operation() };
+    match (git_held, git) {
+      (None, Some(op)) => log::info("Holding changes", format!("git {op} in progress")),
+      (Some(op), None) if !next.is_empty() => log::info(&format!("Git {op} finished"), "building the held changes"),
       _ => (),
     }
+    git_held = git;
+
+    if !rebuild && (next.is_empty() || controls.paused.load(Ordering::Relaxed) || git.is_some()) {
+      let Some(event) = tx.recv_timeout(DAEMON_POLL)
+      else {
+        if let Some(status) = daemon.check()? {
+          log::warn("Daemon exited", format!("{status}, restarting it on the next change"));
+          if !send_keeping_changes(&tx, &mut next, Event::DaemonExited(status)) {
+            break;
+          }
+        }
+        continue;
+      };
+      if let Some(n) = newly_dropped(&tx, &mut dropped) {
+        log::warn("Command runner fell behind", format!("{n} events dropped | {}", tx.stats()));
+      }
+
+      match event {
+        Event::FileChange(change) => add_change(&mut next, change),
+        Event::Quit => break,
+        _ => (),
+      }
+      continue;
+    }
+
+    // Everything that changed while the previous build ran goes into a single run.
+    let mut changes = std::mem::take(&mut next);
+    if !drain_changes(&mut changes, &tx) {
+      break;
+    }
+
+    let Some((status, output)) = build(&mut rules, rebuild, cancel, controls, &mut changes, &mut next, &tx)?
+    else {
+      break;
+    };
+    *build_log.lock().unwrap() = String::from_utf8_lossy(&output).into_owned();
+    if status.success() {
+      // Restart before the reload goes out so the page talks to the new daemon.
+      if let Err(e) = daemon.restart(KILL_GRACE) {
+        log::warn("Couldn't restart the daemon", e);
+      }
+    }
+    else {
+      log::warn("Build failed", format!("{status}, skipping reload"));
+    }
+    if !send_keeping_changes(&tx, &mut next, Event::CmdFinished(status)) {
+      break;
+    }
+
+    if status.success() {
+      let Some((status, _)) = build(std::slice::from_mut(&mut after), true, false, controls, &mut changes, &mut next, &tx)?
+      else {
+        break;
+      };
+      if !status.success() {
+        log::warn("After hook failed", status);
+      }
+    }
+  }
+
+  daemon.stop(KILL_GRACE)
+}
+
+const QUIT_MSG: &[u8] = b"QUIT\r\n";
+
+/// Tells every thread to stop and wakes up the listener (if there's one) so it stops accepting
+/// connections.
+fn quit(tx: &Sender<Event>, addr: Option<SocketAddr>) -> Result<(), Error> {
+  tx.send(Event::Quit);
+  if let Some(addr) = addr {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(QUIT_MSG)?;
+  }
+  Ok(())
+}
+
+/// Applies the `-cwd` and `-env` options.
+fn environment(cmd: &mut Cmd, cli: &Cli) {
+  if let Some(ref cwd) = cli.cwd {
+    cmd.cwd(cwd);
+  }
+  cmd.env(&cli.env);
+}
+
+/// Applies the options shared by every command that runs for changes.
+fn configure(cmd: &mut Cmd, cli: &Cli) {
+  environment(cmd, cli);
+  let root = &cli.dir_watch[0];
+  cmd.root(&root.canonicalize().unwrap_or_else(|_| root.clone()));
+  // Stopping a build on quit or with -cancel also stops what the command started.
+  cmd.process_group();
+  if cli.null {
+    cmd.null();
+  }
+  cmd.limits(cli.limits);
+}
+
+/// The `-run` pipeline followed by the `-on` rules.
+fn rules(cli: &Cli) -> Result<Vec<Rule>, Error> {
+  let mut rules = vec![Rule {
+    glob: None,
+    cmd: Cmd::pipeline(&cli.steps, cli.exec)?,
+  }];
+  for (glob, cmd) in &cli.rules {
+    rules.push(Rule {
+      glob: Some(Glob::new(glob)),
+      cmd: Cmd::new(cmd, cli.exec)?,
+    });
+  }
+
+  for Rule { cmd, .. } in &mut rules {
+    configure(cmd, cli);
+  }
+  Ok(rules)
+}
+
+/// The `-after` hook, as a rule for every change.
+fn after(cli: &Cli) -> Result<Rule, Error> {
+  let mut cmd = Cmd::new(cli.after.as_deref().unwrap_or(""), cli.exec)?;
+  configure(&mut cmd, cli);
+  Ok(Rule { glob: None, cmd })
+}
+
+/// Runs the commands a single time without watching or serving anything, a signal stops them
+/// (they're in their own process groups, out of reach of the terminal's Ctrl-C).
+pub fn run_once(cli: &Cli) -> Result<ExitStatus, Error> {
+  let signals = block_signals();
+  let mut rules = rules(cli)?;
+
+  let (tx, rx) = RingBuffer::channel(cli.capacity, Overflow::Overwrite, Event::Start);
+  thread::spawn(move || {
+    if let Err(e) = handle_signals(signals, &Controls::default(), &tx, None) {
+      log::error("Error shutting down", e);
+    }
+  });
+
+  let Some((status, _)) = build(&mut rules, true, false, &Controls::default(), &mut Vec::new(), &mut Vec::new(), &rx)?
+  else {
+    // Stopped by a signal, exit with 1.
+    return Ok(ExitStatus::from_raw(1 << 8));
+  };
+  if !status.success() {
+    log::warn("Build failed", status);
+  }
+  Ok(status)
+}
+
+/// Blocks SIGINT, SIGTERM, SIGHUP and SIGUSR1 in this thread and the ones it starts, so they're
+/// only received through `sigwait`. Commands start with the default signal mask.
+fn block_signals() -> libc::sigset_t {
+  unsafe {
+    let mut set = std::mem::zeroed();
+    libc::sigemptyset(&mut set);
+    for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP, libc::SIGUSR1] {
+      libc::sigaddset(&mut set, signal);
+    }
+    libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+    set
   }
+}
 
+/// SIGUSR1 pauses or resumes watching. The first other signal in `set` shuts down like pressing Q,
+/// and the next one exits right away.
+fn handle_signals(set: libc::sigset_t, controls: &Controls, tx: &Sender<Event>, addr: Option<SocketAddr>) -> Result<(), Error> {
+  let mut quitting = false;
+  loop {
+    let mut signal = 0;
+    unsafe { libc::sigwait(&set, &mut signal) };
+    match signal {
+      libc::SIGUSR1 => controls.toggle_pause(),
+      _ if quitting => {
+        log::warn("Exiting", format!("{} while shutting down", signal_name(signal)));
+        process::exit(1);
+      }
+      _ => {
+        log::info("Shutting down", signal_name(signal));
+        quit(tx, addr)?;
+        quitting = true;
+      }
+    }
+  }
+}
+
+fn signal_name(signal: i32) -> &'static str {
+  match signal {
+    libc::SIGINT => "SIGINT",
+    libc::SIGTERM => "SIGTERM",
+    libc::SIGHUP => "SIGHUP",
+    _ => "signal",
+  }
+}
+
+const KEYS_HELP: &str = "r rebuild | R reload the browsers | c clear | o open in the browser | p pause/resume | \
+                         l last build log | q quit";
+
+/// Opens `url` with the desktop's default browser.
+fn open_browser(url: &str) -> Result<(), Error> {
+  let opener = if cfg!(target_os = "macos") { "open" } else { "xdg-open" };
+  let mut child = Command::new(opener)
+    .arg(url)
+    .stdin(Stdio::null())
+    .stdout(Stdio::null())
+    .stderr(Stdio::null())
+    .spawn()?;
+  thread::spawn(move || child.wait());
   Ok(())
 }
 
 pub fn run_server(cli: &Cli) -> Result<(), Error> {
-  let listener = TcpListener::bind(format!("0.0.0.0:{}", cli.port))?;
-
-  println!(
-    "\x1b[1m\x1b[38;5;159mhttp://localhost:{}\n\
-     \x1b[38;5;158mhttp://{}:{}\n\n\
-     Watching \x1b[93m{:?}\x1b[37m\n\
-     Serving  \x1b[93m{:?}\x1b[38;5;225m\n\n\
-     Press Q\x1b[0m to exit\n\
-    ",
-    cli.port,
-    listener.local_addr()?.ip(),
-    cli.port,
-    cli.dir_watch,
-    cli.dir_serve,
-  );
-
-  let (tx, rx) = RingBuffer::channel::<32>(Event::Start);
-
-  let dirwatcher = {
-    let dir_watch = cli.dir_watch.clone();
+  // Before any thread starts so none of them gets the signals.
+  let signals = block_signals();
+
+  if let Some(ref path) = cli.config {
+    log::info("Using config", path.display());
+  }
+
+  let mut rules = rules(cli)?;
+  // Connections wait in the backlog until the initial build is done.
+  let listener = if cli.no_serve {
+    None
+  }
+  else {
+    Some(net::listen(SocketAddr::new(cli.host, cli.port))?)
+  };
+  let addr = listener.as_ref().map(TcpListener::local_addr).transpose()?;
+  let controls = Arc::new(Controls::new(&cli.dir_watch));
+
+  let overflow = if cli.lossless { Overflow::Block } else { Overflow::Overwrite };
+  let (tx, rx) = RingBuffer::channel(cli.capacity, overflow, Event::Start);
+  // Without a listener to wake up, the main thread waits for the quit event instead.
+  let quit_rx = listener.is_none().then(|| Receiver::from(&tx));
+
+  {
     let tx = tx.clone();
+    let controls = controls.clone();
 
     thread::spawn(move || {
-      if let Err(e) = dirwatch::watch_dir(&dir_watch, dirwatch::IN_MODIFY, tx) {
-        eprintln!("\x1b[38;5;210mError watching directory:\x1b[0m {e}");
+      if let Err(e) = handle_signals(signals, &controls, &tx, addr) {
+        log::error("Error shutting down", e);
       }
-    })
-  };
+    });
+  }
+
+  let build_log = Arc::new(Mutex::new(String::new()));
+  if cli.initial && !cli.no_watch {
+    log::info("Initial build", "");
+    // On the bus like the other builds, so a signal stops it.
+    let Some((status, output)) = build(&mut rules, true, false, &controls, &mut Vec::new(), &mut Vec::new(), &rx)?
+    else {
+      log::info("Server shutdown", "");
+      return Ok(());
+    };
+    *build_log.lock().unwrap() = String::from_utf8_lossy(&output).into_owned();
+    if !status.success() {
+      log::warn("Build failed", status);
+    }
+  }
+
+  let urls = addr.map(|addr| net::urls(addr, &net::interface_addrs())).unwrap_or_default();
+  for url in &urls {
+    log::info("Listening on", url);
+  }
+  if !cli.no_watch {
+    log::info("Watching", format!("{:?}", cli.dir_watch));
+  }
+  if addr.is_some() {
+    log::info("Serving", format!("{:?}", cli.dir_serve));
+  }
+  // Without a terminal (a service, a container without -t, CI) only signals stop the server.
+  let interactive = io::stdin().is_terminal();
+  if interactive {
+    log::info("Press Q to exit", "or ? for the other keys");
+  }
 
-  let cmd_runner = {
-    let cmd = Cmd::new(&cli.cmd);
-    let tx = rx.clone();
+  let recorder = cli.record.clone().map(|path| {
+    let rx = Receiver::from(&tx);
 
     thread::spawn(move || {
-      if let Err(e) = run_cmd(cmd, tx) {
-        eprintln!("\x1b[38;5;210mCommand execution failed:\x1b[0m {e}");
+      if let Err(e) = record::record_events(&path, rx) {
+        log::error("Error recording events", e);
       }
     })
+  });
+
+  let dirwatchers = if cli.no_watch {
+    Vec::new()
+  }
+  else if let Some(path) = cli.replay.clone() {
+    let tx = tx.clone();
+
+    vec![thread::spawn(move || match record::replay_events(&path, &tx) {
+      Ok(true) => {
+        if let Err(e) = quit(&tx, addr) {
+          log::error("Error quitting after replay", e);
+        }
+      }
+      Ok(false) => log::info("Replay finished", ""),
+      Err(e) => log::error("Error replaying events", e),
+    })]
+  }
+  else {
+    let mut dirwatchers = Vec::new();
+    for dir_watch in cli.dir_watch.clone() {
+      let ignore = cli.ignore.iter().map(|glob| Glob::new(glob)).collect::<Vec<_>>();
+      // Git writes there for status and fetches too, not just for changes to the files.
+      let git_dirs = controls.git_dirs.clone();
+      let tx = tx.clone();
+
+      dirwatchers.push(thread::spawn(move || {
+        if let Err(e) = dirwatch::watch_dir(&dir_watch, WATCH_MASK, &ignore, &git_dirs, tx) {
+          log::error("Error watching directory", e);
+        }
+      }));
+    }
+    dirwatchers
   };
 
-  const QUIT_MSG: &[u8] = b"QUIT\r\n";
-  let key_listener = {
+  // A receiver nobody reads would hold back a lossless bus, so it's dropped when not watching.
+  let cmd_runner = if cli.no_watch {
+    drop(rx);
+    None
+  }
+  else {
+    let after = after(cli)?;
+    let mut daemon = Cmd::new(cli.daemon.as_deref().unwrap_or(""), cli.exec)?;
+    environment(&mut daemon, cli);
+    let daemon = Daemon::new(daemon);
+    let cancel = cli.cancel;
+    let controls = controls.clone();
+    let build_log = build_log.clone();
+
+    Some(thread::spawn(move || {
+      if let Err(e) = run_cmd(rules, after, daemon, cancel, &controls, build_log, rx) {
+        log::error("Command execution failed", e);
+      }
+    }))
+  };
+
+  let key_listener = interactive.then(|| {
     let tx = tx.clone();
-    let addr = listener.local_addr()?;
+    let controls = controls.clone();
+    let build_log = build_log.clone();
+    let url = urls.first().cloned();
 
     thread::spawn(move || -> Result<(), Error> {
       loop {
         match read_key()? {
-          Key::Byte(b) if b.eq_ignore_ascii_case(&b'q') => {
-            tx.send(Event::Quit);
-            let mut stream = TcpStream::connect(addr)?;
-            stream.write_all(QUIT_MSG)?;
+          Key::Byte(b'q' | b'Q') => {
+            quit(&tx, addr)?;
             break;
           }
+          Key::Byte(b'r') => {
+            log::info("Rebuilding", "");
+            controls.rebuild.store(true, Ordering::Relaxed);
+          }
+          Key::Byte(b'R') => tx.send(Event::Reload),
+          Key::Byte(b'c') if log::styled() => {
+            print!("\x1b[2J\x1b[3J\x1b[H");
+            io::stdout().flush()?;
+          }
+          Key::Byte(b'o') => match url {
+            Some(ref url) => {
+              if let Err(e) = open_browser(url) {
+                log::warn("Couldn't open the browser", e);
+              }
+            }
+            None => log::warn("Nothing to open", "the server isn't running"),
+          },
+          Key::Byte(b'p') => controls.toggle_pause(),
+          Key::Byte(b'l') => match build_log.lock().unwrap().as_str() {
+            "" => log::info("No build log yet", ""),
+            build_log => {
+              log::info("Last build log", "");
+              // Relayed like the command output, as `Output` lines with JSON logs.
+              let mut output = log::output(false);
+              if let Err(e) = output.write_all(build_log.as_bytes()).and_then(|()| output.flush()) {
+                log::warn("Couldn't print the build log", e);
+              }
+            }
+          },
+          Key::Byte(b'?') => log::info("Keys", KEYS_HELP),
           _ => (),
         }
       }
 
       Ok(())
     })
-  };
+  });
+
+  if let Some(quit_rx) = quit_rx {
+    while !matches!(quit_rx.recv(), Event::Quit) {}
+  }
 
   let mut stream_peek = [0; 6];
   thread::scope(|s| -> Result<(), Error> {
-    for stream in listener.incoming() {
+    for stream in listener.iter().flat_map(TcpListener::incoming) {
       match stream {
         Ok(stream) => {
           stream.peek(&mut stream_peek)?;
@@ -222,32 +848,103 @@ pub fn run_server(cli: &Cli) -> Result<(), Error> {
           }
 
           let peer_addr = stream.peer_addr()?;
-          let dir_serve = cli.dir_serve.clone();
-          let rx = rx.clone();
+          let controls = &controls;
+          let build_log = &build_log;
+          // Connections block on clients, they'd freeze a lossless bus if they held it back too.
+          let rx = Receiver::lossy(&tx);
 
           s.spawn(move || {
-            if let Err(e) = handle_http(stream, &dir_serve, rx) {
-              eprintln!("[  {}] Error handling request: {}", peer_addr, e);
+            if let Err(e) = handle_http(stream, cli, controls, build_log, rx) {
+              log::peer(peer_addr).error("Error handling request", e);
             }
           });
         }
-        Err(e) => eprintln!("Connection failed: {}", e),
+        Err(e) => log::error("Connection failed", e),
       }
     }
 
     Ok(())
   })?;
 
-  cmd_runner.join().unwrap();
-  key_listener.join().unwrap()?;
-  dirwatcher.join().unwrap();
+  if let Some(cmd_runner) = cmd_runner {
+    cmd_runner.join().unwrap();
+  }
+  // A replay or a signal can quit while the key listener is still waiting for a key.
+  if let Some(key_listener) = key_listener.filter(|k| k.is_finished()) {
+    key_listener.join().unwrap()?;
+  }
+  for dirwatcher in dirwatchers {
+    dirwatcher.join().unwrap();
+  }
+  if let Some(recorder) = recorder {
+    recorder.join().unwrap();
+  }
 
-  println!("Server shutdown");
+  log::debug("Event bus", tx.stats());
+  log::info("Server shutdown", "");
   Ok(())
 }
 
-pub fn send_sse_message(stream: &mut TcpStream) -> Result<(), Error> {
-  stream.write_all(b"data: File changed\n\n")?;
+/// Sends a server sent event, unnamed events (`event` is `None`) reload the page.
+pub fn send_sse_message(stream: &mut TcpStream, event: Option<&str>, data: &str) -> Result<(), Error> {
+  if let Some(event) = event {
+    writeln!(stream, "event: {event}")?;
+  }
+  // A lone CR (like in progress bars) also ends a line for the browser.
+  for line in data.lines().flat_map(|line| line.split('\r')) {
+    writeln!(stream, "data: {line}")?;
+  }
+  stream.write_all(b"\n")?;
   stream.flush()?;
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn replayed_changes_build_once() {
+    let path = std::env::temp_dir().join(format!("dirwatch-replay-{}", process::id()));
+    std::fs::write(
+      &path,
+      "0 FileChange modify src/a.rs\n0 CmdFinished 0\n0 FileChange create src/b.rs\n0 FileChange modify src/a.rs\n0 Quit\n",
+    )
+    .unwrap();
+
+    // Everything is replayed before the runner starts, so it's all waiting for the same build.
+    let (tx, rx) = RingBuffer::channel(32, Overflow::Block, Event::Start);
+    let events = Receiver::from(&tx);
+    assert!(record::replay_events(&path, &tx).unwrap());
+    std::fs::remove_file(&path).unwrap();
+
+    let rules = vec![Rule {
+      glob: None,
+      cmd: Cmd::new("cat", false).unwrap(),
+    }];
+    let after = Rule {
+      glob: None,
+      cmd: Cmd::new("", false).unwrap(),
+    };
+    let daemon = Daemon::new(Cmd::new("", false).unwrap());
+    let build_log = Arc::new(Mutex::new(String::new()));
+    let runner = {
+      let build_log = build_log.clone();
+      thread::spawn(move || run_cmd(rules, after, daemon, false, &Controls::default(), build_log, rx))
+    };
+
+    // The recorded `CmdFinished` isn't replayed, the first one comes from the build.
+    let status = loop {
+      match events.recv() {
+        Event::CmdFinished(status) => break status,
+        Event::FileChange(_) => (),
+        event => panic!("unexpected event {event:?}"),
+      }
+    };
+    tx.send(Event::Quit);
+    runner.join().unwrap().unwrap();
+
+    assert!(status.success());
+    assert_eq!(*build_log.lock().unwrap(), "src/a.rs\nsrc/b.rs\n");
+  }
+}